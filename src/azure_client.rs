@@ -0,0 +1,503 @@
+use super::http_client::{check_status, uri_encode, HttpClient, ResponseFuture};
+use super::storage::StorageError;
+use chrono::Utc;
+use hyper::{HeaderMap, Method, StatusCode};
+use ring::{digest, hmac};
+use std::collections::BTreeMap;
+use std::io;
+use url::Url;
+
+const API_VERSION: &str = "2018-03-28";
+
+// Azurite's account, which every install of it shares
+const DEVELOPMENT_ACCOUNT: &str = "devstoreaccount1";
+const DEVELOPMENT_KEY: &str =
+    "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+const DEVELOPMENT_ENDPOINT: &str = "http://127.0.0.1:10000/devstoreaccount1";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AzureAuth {
+    // the account's master key, which gives full control of the account
+    SharedKey(String),
+    // a shared access signature, which can be limited to one container
+    SasToken(String),
+    // names the account and its endpoint as well as a key or signature
+    ConnectionString(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Credentials {
+    SharedKey(Vec<u8>),
+    SasToken(String),
+}
+
+// Where requests for an account are sent and how they are authorised.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AzureAccount {
+    pub name: String,
+    pub endpoint: String,
+    credentials: Credentials,
+}
+
+impl AzureAccount {
+    // An endpoint given here takes precedence over one in a connection string.
+    pub fn new(
+        name: &str,
+        endpoint: Option<&str>,
+        auth: &AzureAuth,
+    ) -> Result<AzureAccount, StorageError> {
+        let (name, connection_endpoint, credentials) = match auth {
+            AzureAuth::SharedKey(key) => (String::from(name), None, shared_key(key)?),
+            AzureAuth::SasToken(token) => (String::from(name), None, sas_token(token)),
+            AzureAuth::ConnectionString(connection_string) => {
+                parse_connection_string(name, connection_string)?
+            }
+        };
+
+        let endpoint = match (endpoint, connection_endpoint) {
+            (Some(endpoint), _) => String::from(endpoint),
+            (None, Some(endpoint)) => endpoint,
+            (None, None) if !name.is_empty() => {
+                default_endpoint(&name, "https", "core.windows.net")
+            }
+            (None, None) => {
+                return Err(invalid_input(String::from(
+                    "The storage account name or blob endpoint is missing",
+                )))
+            }
+        };
+
+        Ok(AzureAccount {
+            name,
+            endpoint,
+            credentials,
+        })
+    }
+}
+
+// The Blob service REST API, authorised with either the account's Shared Key
+// or a SAS token. The azure_sdk_for_rust client it replaces only ever talked
+// to *.blob.core.windows.net, which leaves out Azurite, the sovereign clouds,
+// private endpoints and Azure Stack, and had no support for SAS tokens.
+pub struct AzureClient {
+    endpoint: String,
+    // path of the endpoint, which Azurite and Azure Stack use for the account
+    endpoint_path: String,
+    account: String,
+    credentials: Credentials,
+    container: String,
+    http: HttpClient,
+}
+
+impl AzureClient {
+    pub fn new(account: AzureAccount, container: &str) -> Result<AzureClient, StorageError> {
+        let endpoint = account.endpoint.trim_end_matches('/');
+        let url = Url::parse(endpoint).map_err(|e| invalid_input(e.to_string()))?;
+
+        Ok(AzureClient {
+            endpoint: String::from(endpoint),
+            endpoint_path: url.path().trim_end_matches('/').to_owned(),
+            account: account.name,
+            credentials: account.credentials,
+            container: String::from(container),
+            http: HttpClient::new()?,
+        })
+    }
+
+    // Blob names are already percent encoded. A SAS token is included, as
+    // the url is used as the source of copies.
+    pub fn blob_url(&self, blob_name: &str) -> String {
+        let url = format!("{}/{}/{}", self.endpoint, self.container, blob_name);
+        match self.credentials {
+            Credentials::SasToken(ref token) => format!("{}?{}", url, token),
+            Credentials::SharedKey(_) => url,
+        }
+    }
+
+    // An authorised request for a blob, or for the container when there is
+    // no blob name.
+    pub fn request(
+        &self,
+        method: Method,
+        blob_name: Option<&str>,
+        query: &[(&str, &str)],
+        extra_headers: &[(&str, String)],
+        body: Vec<u8>,
+    ) -> ResponseFuture {
+        let mut path = format!("{}/{}", self.endpoint_path, self.container);
+        if let Some(blob_name) = blob_name {
+            path = format!("{}/{}", path, blob_name);
+        }
+
+        let mut headers = BTreeMap::new();
+        headers.insert(
+            String::from("x-ms-date"),
+            Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+        );
+        headers.insert(String::from("x-ms-version"), String::from(API_VERSION));
+        for (name, value) in extra_headers {
+            headers.insert(name.to_lowercase(), value.clone());
+        }
+        // hyper leaves out the length of empty bodies, which Azure requires
+        // on every PUT
+        if method == Method::PUT || !body.is_empty() {
+            headers.insert(String::from("content-length"), body.len().to_string());
+        }
+
+        if let Credentials::SharedKey(ref key) = self.credentials {
+            let signature = hmac_sha256(
+                key,
+                string_to_sign(
+                    method.as_str(),
+                    &headers,
+                    &canonicalized_resource(&self.account, &path, query),
+                )
+                .as_bytes(),
+            );
+            headers.insert(
+                String::from("authorization"),
+                format!("SharedKey {}:{}", self.account, base64::encode(&signature)),
+            );
+        }
+
+        let mut uri = format!("{}{}", self.endpoint, &path[self.endpoint_path.len()..]);
+        let mut query: Vec<String> = query
+            .iter()
+            .map(|&(k, v)| format!("{}={}", uri_encode(k, true), uri_encode(v, true)))
+            .collect();
+        // SAS tokens are already encoded
+        if let Credentials::SasToken(ref token) = self.credentials {
+            query.push(token.clone());
+        }
+        if !query.is_empty() {
+            uri = format!("{}?{}", uri, query.join("&"));
+        }
+
+        self.http
+            .request(method, &uri, headers.into_iter().collect(), body)
+    }
+
+    pub fn send(
+        &self,
+        method: Method,
+        blob_name: Option<&str>,
+        query: &[(&str, &str)],
+        extra_headers: &[(&str, String)],
+        body: Vec<u8>,
+        expected: StatusCode,
+    ) -> Result<(HeaderMap, Vec<u8>), StorageError> {
+        let description = format!("{} {}", method, blob_name.unwrap_or(&self.container));
+        let response = self.run(self.request(method, blob_name, query, extra_headers, body))?;
+        check_status(&description, response, expected)
+    }
+
+    pub fn run<T>(
+        &self,
+        future: Box<::futures::Future<Item = T, Error = StorageError>>,
+    ) -> Result<T, StorageError> {
+        self.http.run(future)
+    }
+}
+
+fn string_to_sign(method: &str, headers: &BTreeMap<String, String>, resource: &str) -> String {
+    let header = |name: &str| headers.get(name).map(|v| v.as_str()).unwrap_or("");
+    // a zero length is signed as empty since version 2015-02-21
+    let content_length = match header("content-length") {
+        "0" => "",
+        len => len,
+    };
+    let canonicalized_headers: String = headers
+        .iter()
+        .filter(|&(name, _)| name.starts_with("x-ms-"))
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}{}",
+        method,
+        header("content-encoding"),
+        header("content-language"),
+        content_length,
+        header("content-md5"),
+        header("content-type"),
+        header("date"),
+        header("if-modified-since"),
+        header("if-match"),
+        header("if-none-match"),
+        header("if-unmodified-since"),
+        header("range"),
+        canonicalized_headers,
+        resource
+    )
+}
+
+fn canonicalized_resource(account: &str, path: &str, query: &[(&str, &str)]) -> String {
+    let mut query: Vec<(String, &str)> =
+        query.iter().map(|&(k, v)| (k.to_lowercase(), v)).collect();
+    query.sort();
+
+    query
+        .iter()
+        .fold(format!("/{}{}", account, path), |resource, &(ref k, v)| {
+            format!("{}\n{}:{}", resource, k, v)
+        })
+}
+
+fn default_endpoint(account: &str, protocol: &str, suffix: &str) -> String {
+    format!("{}://{}.blob.{}", protocol, account, suffix)
+}
+
+fn shared_key(key: &str) -> Result<Credentials, StorageError> {
+    base64::decode(key)
+        .map(Credentials::SharedKey)
+        .map_err(|e| invalid_input(format!("The account key is not valid base64 - {}", e)))
+}
+
+fn sas_token(token: &str) -> Credentials {
+    Credentials::SasToken(token.trim_start_matches('?').to_owned())
+}
+
+// Connection strings are `;` separated `name=value` settings, as shown in the
+// Azure portal, for example
+// `DefaultEndpointsProtocol=https;AccountName=name;AccountKey=key;EndpointSuffix=core.windows.net`
+fn parse_connection_string(
+    name: &str,
+    connection_string: &str,
+) -> Result<(String, Option<String>, Credentials), StorageError> {
+    let settings: BTreeMap<&str, &str> = connection_string
+        .split(';')
+        .filter(|s| !s.trim().is_empty())
+        .filter_map(|s| {
+            let mut parts = s.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(k), Some(v)) => Some((k.trim(), v.trim())),
+                _ => None,
+            }
+        })
+        .collect();
+
+    if settings.get("UseDevelopmentStorage") == Some(&"true") {
+        return Ok((
+            String::from(DEVELOPMENT_ACCOUNT),
+            Some(String::from(DEVELOPMENT_ENDPOINT)),
+            shared_key(DEVELOPMENT_KEY)?,
+        ));
+    }
+
+    let name = settings
+        .get("AccountName")
+        .map(|n| String::from(*n))
+        .unwrap_or_else(|| String::from(name));
+    let endpoint = match settings.get("BlobEndpoint") {
+        Some(endpoint) => Some(String::from(*endpoint)),
+        None if !name.is_empty() => Some(default_endpoint(
+            &name,
+            settings.get("DefaultEndpointsProtocol").unwrap_or(&"https"),
+            settings
+                .get("EndpointSuffix")
+                .unwrap_or(&"core.windows.net"),
+        )),
+        None => None,
+    };
+    let credentials = match (
+        settings.get("AccountKey"),
+        settings.get("SharedAccessSignature"),
+    ) {
+        (Some(key), _) => shared_key(key)?,
+        (None, Some(token)) => sas_token(token),
+        (None, None) => {
+            return Err(invalid_input(String::from(
+                "The connection string has neither an AccountKey nor a SharedAccessSignature",
+            )))
+        }
+    };
+
+    Ok((name, endpoint, credentials))
+}
+
+fn invalid_input(message: String) -> StorageError {
+    StorageError::from(io::Error::new(io::ErrorKind::InvalidInput, message))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let key = hmac::SigningKey::new(&digest::SHA256, key);
+    hmac::sign(&key, data).as_ref().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|&(k, v)| (String::from(k), String::from(v)))
+            .collect()
+    }
+
+    #[test]
+    fn test_string_to_sign_for_put_blob() {
+        let headers = headers(&[
+            ("content-length", "5"),
+            ("content-md5", "XUFAKrxLKna5cZ2REBfFkg=="),
+            ("if-match", "\"0x8D5\""),
+            ("x-ms-blob-type", "BlockBlob"),
+            ("x-ms-date", "Tue, 04 Sep 2018 10:00:00 GMT"),
+            ("x-ms-version", "2018-03-28"),
+        ]);
+
+        assert_eq!(
+            string_to_sign("PUT", &headers, "/account/container/file.txt"),
+            "PUT\n\n\n5\nXUFAKrxLKna5cZ2REBfFkg==\n\n\n\n\"0x8D5\"\n\n\n\n\
+             x-ms-blob-type:BlockBlob\n\
+             x-ms-date:Tue, 04 Sep 2018 10:00:00 GMT\n\
+             x-ms-version:2018-03-28\n\
+             /account/container/file.txt"
+        );
+    }
+
+    #[test]
+    fn test_empty_content_length_is_not_signed() {
+        let headers = headers(&[("content-length", "0")]);
+
+        assert_eq!(
+            string_to_sign("PUT", &headers, "/account/container"),
+            "PUT\n\n\n\n\n\n\n\n\n\n\n\n/account/container"
+        );
+    }
+
+    #[test]
+    fn test_canonicalized_resource_sorts_query() {
+        assert_eq!(
+            canonicalized_resource(
+                "account",
+                "/container",
+                &[
+                    ("restype", "container"),
+                    ("comp", "list"),
+                    ("prefix", "my folder/")
+                ]
+            ),
+            "/account/container\ncomp:list\nprefix:my folder/\nrestype:container"
+        );
+    }
+
+    fn account(name: &str, endpoint: Option<&str>, auth: AzureAuth) -> AzureAccount {
+        AzureAccount::new(name, endpoint, &auth).unwrap()
+    }
+
+    #[test]
+    fn test_endpoint_path_is_part_of_the_resource() {
+        let client = AzureClient::new(
+            account(
+                "devstoreaccount1",
+                Some("http://127.0.0.1:10000/devstoreaccount1/"),
+                AzureAuth::SharedKey(base64::encode(b"key")),
+            ),
+            "container",
+        )
+        .unwrap();
+
+        assert_eq!(
+            client.blob_url("my%20file.txt"),
+            "http://127.0.0.1:10000/devstoreaccount1/container/my%20file.txt"
+        );
+        assert_eq!(client.endpoint_path, "/devstoreaccount1");
+    }
+
+    #[test]
+    fn test_invalid_key_is_rejected() {
+        assert!(AzureAccount::new(
+            "account",
+            None,
+            &AzureAuth::SharedKey(String::from("not base64!"))
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_shared_key_uses_public_endpoint_by_default() {
+        let account = account(
+            "account",
+            None,
+            AzureAuth::SharedKey(base64::encode(b"key")),
+        );
+
+        assert_eq!(account.endpoint, "https://account.blob.core.windows.net");
+        assert_eq!(account.credentials, Credentials::SharedKey(b"key".to_vec()));
+    }
+
+    #[test]
+    fn test_sas_token_is_added_to_urls() {
+        let client = AzureClient::new(
+            account(
+                "account",
+                None,
+                AzureAuth::SasToken(String::from("?sv=2018-03-28&sr=c&sig=a%2Bb")),
+            ),
+            "container",
+        )
+        .unwrap();
+
+        assert_eq!(
+            client.blob_url("file.txt"),
+            "https://account.blob.core.windows.net/container/file.txt?sv=2018-03-28&sr=c&sig=a%2Bb"
+        );
+    }
+
+    #[test]
+    fn test_connection_string_with_account_key() {
+        let account = account(
+            "",
+            None,
+            AzureAuth::ConnectionString(format!(
+                "DefaultEndpointsProtocol=https;AccountName=gov;AccountKey={};EndpointSuffix=core.usgovcloudapi.net",
+                base64::encode(b"key")
+            )),
+        );
+
+        assert_eq!(account.name, "gov");
+        assert_eq!(account.endpoint, "https://gov.blob.core.usgovcloudapi.net");
+        assert_eq!(account.credentials, Credentials::SharedKey(b"key".to_vec()));
+    }
+
+    #[test]
+    fn test_connection_string_with_sas_token() {
+        let account = account(
+            "account",
+            None,
+            AzureAuth::ConnectionString(String::from(
+                "BlobEndpoint=https://private.example.com;SharedAccessSignature=sv=2018-03-28&sig=abc",
+            )),
+        );
+
+        assert_eq!(account.name, "account");
+        assert_eq!(account.endpoint, "https://private.example.com");
+        assert_eq!(
+            account.credentials,
+            Credentials::SasToken(String::from("sv=2018-03-28&sig=abc"))
+        );
+    }
+
+    #[test]
+    fn test_configured_endpoint_overrides_connection_string() {
+        let account = account(
+            "",
+            Some("https://stack.example.com"),
+            AzureAuth::ConnectionString(String::from("UseDevelopmentStorage=true")),
+        );
+
+        assert_eq!(account.name, DEVELOPMENT_ACCOUNT);
+        assert_eq!(account.endpoint, "https://stack.example.com");
+    }
+
+    #[test]
+    fn test_connection_string_without_credentials_is_rejected() {
+        assert!(AzureAccount::new(
+            "",
+            None,
+            &AzureAuth::ConnectionString(String::from("AccountName=account"))
+        )
+        .is_err());
+    }
+}
@@ -1,21 +1,50 @@
-use super::event_handlers::{CreatedEvent, EventHandler, RemovedEvent, UpdatedEvent};
+use super::azure_client::AzureAuth;
+use super::event_handlers::{
+    CreatedEvent, DownloadEvent, EventDispatcher, EventHandler, RemoteRemovedEvent, RemovedEvent,
+    RenamedEvent, UpdatedEvent,
+};
 use super::file_system;
+use super::local_storage::LocalStorage;
+use super::reconcile::{self, Action, ReconcilePolicy};
+use super::remote_watcher::{RemoteEvent, RemoteWatcher};
+use super::retry::{RetryPolicy, RetryingStorage};
+use super::s3_storage::{S3Config, S3Storage};
 use super::storage;
+use super::sync_state::{self, SyncState};
+use super::worker_pool::{self, WorkerPool};
 use failure::err_msg;
 use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
 use sentry::integrations::failure::capture_error;
-use std::sync::mpsc::{channel, Receiver};
-use std::time::Duration;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageBackend {
+    Azure,
+    S3(S3Config),
+    Local(String),
+}
+
+#[derive(Clone)]
 pub struct Config {
     pub root_folder: String,
+    pub storage_backend: StorageBackend,
     pub storage_account: String,
-    pub account_key: String,
+    pub azure_auth: AzureAuth,
     pub root_container_name: String,
+    // defaults to the account's own endpoint in the public cloud
+    pub blob_endpoint: Option<String>,
+    pub poll_interval: Duration,
+    pub reconcile_policy: ReconcilePolicy,
+    pub block_size: usize,
+    pub retry_policy: RetryPolicy,
+    pub workers: usize,
 }
 
-pub fn start() {
-    let config = get_default_config();
+pub fn start(config: Config) {
     let (tx, rx) = channel();
     let mut watcher = watcher(tx, Duration::from_secs(10)).unwrap();
 
@@ -27,106 +56,268 @@ pub fn start() {
         }
     }
 
-    event_loop(&rx, &config);
+    let sync_state =
+        Arc::new(SyncState::load(&config.root_folder).expect("Unable to load sync state"));
+
+    let pool = start_workers(&config, &sync_state);
+
+    let remote_config = config.clone();
+    let remote_pool = pool.clone();
+    thread::spawn(move || remote_event_loop(&remote_config, &remote_pool));
+
+    event_loop(&rx, &mut watcher, &config, &sync_state, &pool);
+}
+
+fn start_workers(config: &Config, sync_state: &Arc<SyncState>) -> WorkerPool {
+    let exclusive_file_system = file_system::LocalFileSystem::new(config);
+    let exclusive_sync_state = sync_state.clone();
+    let worker_config = config.clone();
+    let worker_sync_state = sync_state.clone();
+
+    WorkerPool::new(
+        config.workers,
+        move |event_name, path| {
+            is_exclusive(
+                event_name,
+                path,
+                &exclusive_file_system,
+                &exclusive_sync_state,
+            )
+        },
+        move |jobs| {
+            let storage = RetryingStorage::new(
+                connect_storage(&worker_config),
+                worker_config.retry_policy.clone(),
+            );
+            let file_system = file_system::LocalFileSystem::new(&worker_config);
+            let evts = initialise_event_handlers(&storage, &file_system, &worker_sync_state);
+            worker_pool::run_jobs(jobs, &evts);
+        },
+    )
+}
+
+// a removed path that was never synced as a file is most likely a folder,
+// which deletes every blob under it, so it can't run alongside other events
+fn is_exclusive(
+    event_name: &str,
+    path: &PathBuf,
+    file_system: &file_system::FileSystem,
+    sync_state: &SyncState,
+) -> bool {
+    event_name == "remove"
+        && match file_system.get_blob_name(path) {
+            Ok(blob_name) => sync_state.get(&blob_name).is_none(),
+            Err(_) => false,
+        }
 }
 
-fn event_loop(rx: &Receiver<DebouncedEvent>, config: &Config) {
-    let storage = storage::AzureStorage::new(config);
+fn event_loop<W: Watcher>(
+    rx: &Receiver<DebouncedEvent>,
+    watcher: &mut W,
+    config: &Config,
+    sync_state: &SyncState,
+    pool: &WorkerPool,
+) {
+    let storage = RetryingStorage::new(connect_storage(config), config.retry_policy.clone());
     let file_system = file_system::LocalFileSystem::new(config);
-    let evts = initialise_event_handlers(&storage, &file_system);
+    let evts = initialise_event_handlers(&storage, &file_system, sync_state);
+    let reconcile_all = || {
+        if let Err(e) = reconcile(
+            &storage,
+            &file_system,
+            sync_state,
+            pool,
+            &config.reconcile_policy,
+        ) {
+            capture_error(&err_msg(e.to_string()));
+            trace!("reconcile error: {:?}", e);
+        }
+    };
+
+    // remote events are held back until the replay is done
+    pool.run_exclusive(|| evts.replay_queued());
+    reconcile_all();
+
+    let mut last_replay = Instant::now();
+    loop {
+        match rx.recv_timeout(config.poll_interval) {
+            // the watcher dropped events, so compare everything again
+            Ok(DebouncedEvent::Rescan) => reconcile_all(),
+            Ok(DebouncedEvent::Error(e, path)) => {
+                capture_error(&err_msg(e.to_string()));
+                trace!("watch error on {:?}: {:?}", path, e);
+                rewatch(watcher, &config.root_folder, Duration::from_secs(10));
+                reconcile_all();
+            }
+            Ok(event) => route_event(&event, &config.root_folder, pool),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        // operations queued while storage was unavailable are tried again
+        if last_replay.elapsed() >= config.poll_interval {
+            // replayed operations can't overtake events sent to the workers
+            // before them, or be overtaken by remote events sent meanwhile
+            pool.run_exclusive(|| evts.replay_queued());
+            last_replay = Instant::now();
+        }
+    }
+}
+
+// a thread that can't create its storage client keeps trying rather than
+// exiting, so the events sent to it are still handled once it can
+fn connect_storage(config: &Config) -> Box<storage::Storage> {
+    loop {
+        match try_connect_storage(config) {
+            Ok(storage) => return storage,
+            Err(e) => {
+                capture_error(&err_msg(e.to_string()));
+                trace!("storage error: {:?}", e);
+                thread::sleep(config.poll_interval);
+            }
+        }
+    }
+}
+
+fn try_connect_storage(config: &Config) -> Result<Box<storage::Storage>, storage::StorageError> {
+    Ok(match config.storage_backend {
+        StorageBackend::Azure => Box::new(storage::AzureStorage::new(config)?),
+        StorageBackend::S3(ref s3) => Box::new(S3Storage::new(config, s3)?),
+        StorageBackend::Local(ref folder) => Box::new(LocalStorage::new(config, folder)?),
+    })
+}
 
-    for event in rx {
-        route_event(&event, &evts);
+fn rewatch<W: Watcher>(watcher: &mut W, root_folder: &str, retry_delay: Duration) {
+    let _ = watcher.unwatch(root_folder);
+    while let Err(e) = watcher.watch(root_folder, RecursiveMode::Recursive) {
+        capture_error(&err_msg(e.to_string()));
+        trace!("rewatch error: {:?}", e);
+        thread::sleep(retry_delay);
     }
 }
 
-fn route_event(evt: &DebouncedEvent, evts: &EventHandler) {
+fn route_event(evt: &DebouncedEvent, root_folder: &str, evts: &EventDispatcher) {
     match evt {
+        // bucket's own bookkeeping is never synced
+        DebouncedEvent::Create(p) | DebouncedEvent::Remove(p) | DebouncedEvent::Write(p)
+            if sync_state::is_state_path(root_folder, p) => {}
+        DebouncedEvent::Rename(from, to)
+            if sync_state::is_state_path(root_folder, from)
+                || sync_state::is_state_path(root_folder, to) => {}
         DebouncedEvent::Create(p) => evts.call("create", p),
         DebouncedEvent::Remove(p) => evts.call("remove", p),
         DebouncedEvent::Write(p) => evts.call("update", p),
-        _ => (), // only interested in the Create, Remove and Write events
+        DebouncedEvent::Rename(from, to) => evts.call_rename("rename", from, to),
+        _ => (), // only interested in the Create, Remove, Write and Rename events
     }
 }
 
-fn get_default_config() -> Config {
-    Config {
-        root_folder: std::env::var("ROOT_FOLDER").expect("Set env variable ROOT_FOLDER"),
-        storage_account: std::env::var("STORAGE_ACCOUNT")
-            .expect("Set env variable STORAGE_ACCOUNT"),
-        account_key: std::env::var("STORAGE_MASTER_KEY")
-            .expect("Set env variable STORAGE_MASTER_KEY"),
-        root_container_name: std::env::var("STORAGE_CONTAINER")
-            .expect("Set env variable STORAGE_CONTAINER"),
+fn remote_event_loop(config: &Config, pool: &WorkerPool) {
+    let storage = RetryingStorage::new(connect_storage(config), config.retry_policy.clone());
+    let file_system = file_system::LocalFileSystem::new(config);
+    let mut remote_watcher = RemoteWatcher::new();
+
+    loop {
+        match remote_watcher.poll(&storage) {
+            Ok(events) => {
+                for event in events {
+                    route_remote_event(&event, &file_system, pool);
+                }
+            }
+            Err(e) => {
+                capture_error(&err_msg(e.to_string()));
+                trace!("remote watch error: {:?}", e);
+            }
+        }
+        thread::sleep(config.poll_interval);
+    }
+}
+
+fn reconcile(
+    storage: &storage::Storage,
+    file_system: &file_system::FileSystem,
+    sync_state: &SyncState,
+    evts: &EventDispatcher,
+    policy: &ReconcilePolicy,
+) -> Result<(), storage::StorageError> {
+    for action in reconcile::plan(storage, file_system, sync_state, policy)? {
+        route_action(&action, file_system, evts);
+    }
+    Ok(())
+}
+
+fn route_action(action: &Action, file_system: &file_system::FileSystem, evts: &EventDispatcher) {
+    match action {
+        Action::Upload(b) => call_for_blob("create", b, file_system, evts),
+        Action::Download(b) => download_blob(b, file_system, evts),
+        Action::DeleteRemote(b) => call_for_blob("remove", b, file_system, evts),
+        Action::DeleteLocal(b) => call_for_blob("remote_remove", b, file_system, evts),
+    }
+}
+
+fn route_remote_event(
+    evt: &RemoteEvent,
+    file_system: &file_system::FileSystem,
+    evts: &EventDispatcher,
+) {
+    // listed blob names are not encoded
+    match evt {
+        RemoteEvent::Create(b) | RemoteEvent::Update(b) => {
+            download_blob(&file_system.encode_file_name(b), file_system, evts)
+        }
+        RemoteEvent::Remove(b) => call_for_blob(
+            "remote_remove",
+            &file_system.encode_file_name(b),
+            file_system,
+            evts,
+        ),
+    }
+}
+
+fn download_blob(blob_name: &str, file_system: &file_system::FileSystem, evts: &EventDispatcher) {
+    call_for_blob("download", blob_name, file_system, evts);
+}
+
+// blobs whose names would reach outside the root folder are never synced
+fn call_for_blob(
+    event_name: &str,
+    blob_name: &str,
+    file_system: &file_system::FileSystem,
+    evts: &EventDispatcher,
+) {
+    match file_system.get_file_path(blob_name) {
+        Ok(path) => evts.call(event_name, &path),
+        Err(e) => {
+            capture_error(&err_msg(e.to_string()));
+            trace!("Skipping {} - {}", blob_name, e);
+        }
     }
 }
 
 fn initialise_event_handlers<'a>(
     storage: &'a storage::Storage,
     file_system: &'a file_system::FileSystem,
+    sync_state: &'a SyncState,
 ) -> EventHandler<'a> {
-    let mut e = EventHandler::new(storage, file_system);
+    let mut e = EventHandler::new(storage, file_system, sync_state);
     e.add("create", &CreatedEvent {});
     e.add("remove", &RemovedEvent {});
     e.add("update", &UpdatedEvent {});
+    e.add("download", &DownloadEvent {});
+    e.add("remote_remove", &RemoteRemovedEvent {});
+    e.add_rename("rename", &RenamedEvent {});
     e
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use event_handlers::PathEventHandler;
+    use event_handlers::{PathEventHandler, RenameEventHandler};
+    use notify;
     use std::cell::RefCell;
-    use std::path::PathBuf;
-
-    struct MockStorage {}
-
-    impl MockStorage {
-        fn new() -> MockStorage {
-            MockStorage {}
-        }
-    }
-
-    impl storage::Storage for MockStorage {
-        fn upload(&self, blob_name: &str, data: Vec<u8>) -> Result<(), storage::StorageError> {
-            Ok(())
-        }
-        fn download(&self, p: &PathBuf) {}
-        fn delete(&self, blob_name: &str) -> Result<(), storage::StorageError> {
-            Ok(())
-        }
-        fn list_folder_blobs(&self, blob_name: &str) -> Result<Vec<String>, storage::StorageError> {
-            Ok(Vec::new())
-        }
-    }
-
-    struct MockFileSystem {
-        get_blob_name_called: RefCell<bool>,
-        get_file_contents_called: RefCell<bool>,
-    }
-
-    impl MockFileSystem {
-        fn new() -> MockFileSystem {
-            MockFileSystem {
-                get_blob_name_called: RefCell::new(false),
-                get_file_contents_called: RefCell::new(false),
-            }
-        }
-    }
-
-    impl file_system::FileSystem for MockFileSystem {
-        fn get_blob_name(&self, p: &PathBuf) -> String {
-            *self.get_blob_name_called.borrow_mut() = true;
-            String::from("")
-        }
-        fn get_file_contents(&self, p: &PathBuf) -> Vec<u8> {
-            *self.get_file_contents_called.borrow_mut() = true;
-            Vec::new()
-        }
-        fn encode_file_name(&self, f: &str) -> String {
-            String::from("")
-        }
-    }
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc::Sender;
+    use test_support::{MemoryFileSystem, MemoryStorage};
 
     struct MockPathEventHandler {
         called: RefCell<bool>,
@@ -146,24 +337,76 @@ mod tests {
             _path: &PathBuf,
             _storage: &storage::Storage,
             _file_system: &file_system::FileSystem,
+            _sync_state: &sync_state::SyncState,
+        ) {
+            *self.called.borrow_mut() = true;
+        }
+    }
+
+    impl RenameEventHandler for MockPathEventHandler {
+        fn handle(
+            &self,
+            _from: &PathBuf,
+            _to: &PathBuf,
+            _storage: &storage::Storage,
+            _file_system: &file_system::FileSystem,
+            _sync_state: &sync_state::SyncState,
         ) {
             *self.called.borrow_mut() = true;
         }
     }
 
+    struct MockWatcher {
+        watch_failures: RefCell<u32>,
+        watch_calls: RefCell<u32>,
+        unwatch_calls: RefCell<u32>,
+    }
+
+    impl MockWatcher {
+        fn failing(watch_failures: u32) -> MockWatcher {
+            MockWatcher {
+                watch_failures: RefCell::new(watch_failures),
+                watch_calls: RefCell::new(0),
+                unwatch_calls: RefCell::new(0),
+            }
+        }
+    }
+
+    impl Watcher for MockWatcher {
+        fn new_raw(_tx: Sender<notify::RawEvent>) -> notify::Result<MockWatcher> {
+            Ok(MockWatcher::failing(0))
+        }
+        fn new(_tx: Sender<DebouncedEvent>, _delay: Duration) -> notify::Result<MockWatcher> {
+            Ok(MockWatcher::failing(0))
+        }
+        fn watch<P: AsRef<Path>>(&mut self, _path: P, _mode: RecursiveMode) -> notify::Result<()> {
+            *self.watch_calls.borrow_mut() += 1;
+            if *self.watch_failures.borrow() > 0 {
+                *self.watch_failures.borrow_mut() -= 1;
+                return Err(notify::Error::PathNotFound);
+            }
+            Ok(())
+        }
+        fn unwatch<P: AsRef<Path>>(&mut self, _path: P) -> notify::Result<()> {
+            *self.unwatch_calls.borrow_mut() += 1;
+            Ok(())
+        }
+    }
+
     #[test]
     fn test_create_event_calls_create_handler() {
-        let mock_file_system = MockFileSystem::new();
-        let mock_storage = MockStorage::new();
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
         let mock_create_handler = MockPathEventHandler::new();
         let mock_remove_handler = MockPathEventHandler::new();
         let mock_update_handler = MockPathEventHandler::new();
-        let mut e = EventHandler::new(&mock_storage, &mock_file_system);
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
         e.add("create", &mock_create_handler);
         e.add("remove", &mock_remove_handler);
         e.add("update", &mock_update_handler);
 
-        route_event(&DebouncedEvent::Create(PathBuf::new()), &e);
+        route_event(&DebouncedEvent::Create(PathBuf::new()), "/bucket", &e);
 
         assert_eq!(*mock_create_handler.called.borrow(), true);
         assert_eq!(*mock_remove_handler.called.borrow(), false);
@@ -172,17 +415,18 @@ mod tests {
 
     #[test]
     fn test_remove_event_calls_remove_handler() {
-        let mock_file_system = MockFileSystem::new();
-        let mock_storage = MockStorage::new();
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
         let mock_create_handler = MockPathEventHandler::new();
         let mock_remove_handler = MockPathEventHandler::new();
         let mock_update_handler = MockPathEventHandler::new();
-        let mut e = EventHandler::new(&mock_storage, &mock_file_system);
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
         e.add("create", &mock_create_handler);
         e.add("remove", &mock_remove_handler);
         e.add("update", &mock_update_handler);
 
-        route_event(&DebouncedEvent::Remove(PathBuf::new()), &e);
+        route_event(&DebouncedEvent::Remove(PathBuf::new()), "/bucket", &e);
 
         assert_eq!(*mock_create_handler.called.borrow(), false);
         assert_eq!(*mock_remove_handler.called.borrow(), true);
@@ -191,17 +435,18 @@ mod tests {
 
     #[test]
     fn test_write_event_calls_update_handler() {
-        let mock_file_system = MockFileSystem::new();
-        let mock_storage = MockStorage::new();
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
         let mock_create_handler = MockPathEventHandler::new();
         let mock_remove_handler = MockPathEventHandler::new();
         let mock_update_handler = MockPathEventHandler::new();
-        let mut e = EventHandler::new(&mock_storage, &mock_file_system);
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
         e.add("create", &mock_create_handler);
         e.add("remove", &mock_remove_handler);
         e.add("update", &mock_update_handler);
 
-        route_event(&DebouncedEvent::Write(PathBuf::new()), &e);
+        route_event(&DebouncedEvent::Write(PathBuf::new()), "/bucket", &e);
 
         assert_eq!(*mock_create_handler.called.borrow(), false);
         assert_eq!(*mock_remove_handler.called.borrow(), false);
@@ -210,20 +455,229 @@ mod tests {
 
     #[test]
     fn test_ignored_event_does_not_call_event_handler() {
-        let mock_file_system = MockFileSystem::new();
-        let mock_storage = MockStorage::new();
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
         let mock_create_handler = MockPathEventHandler::new();
         let mock_remove_handler = MockPathEventHandler::new();
         let mock_update_handler = MockPathEventHandler::new();
-        let mut e = EventHandler::new(&mock_storage, &mock_file_system);
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
         e.add("create", &mock_create_handler);
         e.add("remove", &mock_remove_handler);
         e.add("update", &mock_update_handler);
 
-        route_event(&DebouncedEvent::NoticeWrite(PathBuf::new()), &e);
+        route_event(&DebouncedEvent::NoticeWrite(PathBuf::new()), "/bucket", &e);
 
         assert_eq!(*mock_create_handler.called.borrow(), false);
         assert_eq!(*mock_remove_handler.called.borrow(), false);
         assert_eq!(*mock_update_handler.called.borrow(), false);
     }
+
+    #[test]
+    fn test_download_blob_calls_download_handler() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let mock_create_handler = MockPathEventHandler::new();
+        let mock_download_handler = MockPathEventHandler::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        e.add("create", &mock_create_handler);
+        e.add("download", &mock_download_handler);
+
+        download_blob("folder/file.txt", &file_system, &e);
+
+        assert_eq!(*mock_create_handler.called.borrow(), false);
+        assert_eq!(*mock_download_handler.called.borrow(), true);
+    }
+
+    #[test]
+    fn test_blob_outside_root_folder_is_not_downloaded() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let mock_download_handler = MockPathEventHandler::new();
+        let mock_remote_remove_handler = MockPathEventHandler::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        e.add("download", &mock_download_handler);
+        e.add("remote_remove", &mock_remote_remove_handler);
+
+        route_remote_event(
+            &RemoteEvent::Create(String::from("../../.bashrc")),
+            &file_system,
+            &e,
+        );
+        route_remote_event(
+            &RemoteEvent::Remove(String::from("../../.bashrc")),
+            &file_system,
+            &e,
+        );
+
+        assert_eq!(*mock_download_handler.called.borrow(), false);
+        assert_eq!(*mock_remote_remove_handler.called.borrow(), false);
+    }
+
+    #[test]
+    fn test_remote_update_event_calls_download_handler() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let mock_download_handler = MockPathEventHandler::new();
+        let mock_remote_remove_handler = MockPathEventHandler::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        e.add("download", &mock_download_handler);
+        e.add("remote_remove", &mock_remote_remove_handler);
+
+        route_remote_event(
+            &RemoteEvent::Update(String::from("file.txt")),
+            &file_system,
+            &e,
+        );
+
+        assert_eq!(*mock_download_handler.called.borrow(), true);
+        assert_eq!(*mock_remote_remove_handler.called.borrow(), false);
+    }
+
+    #[test]
+    fn test_remote_remove_event_calls_remote_remove_handler() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let mock_download_handler = MockPathEventHandler::new();
+        let mock_remote_remove_handler = MockPathEventHandler::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        e.add("download", &mock_download_handler);
+        e.add("remote_remove", &mock_remote_remove_handler);
+
+        route_remote_event(
+            &RemoteEvent::Remove(String::from("file.txt")),
+            &file_system,
+            &e,
+        );
+
+        assert_eq!(*mock_download_handler.called.borrow(), false);
+        assert_eq!(*mock_remote_remove_handler.called.borrow(), true);
+    }
+
+    #[test]
+    fn test_upload_action_calls_create_handler() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let mock_create_handler = MockPathEventHandler::new();
+        let mock_remove_handler = MockPathEventHandler::new();
+        let mock_download_handler = MockPathEventHandler::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        e.add("create", &mock_create_handler);
+        e.add("remove", &mock_remove_handler);
+        e.add("download", &mock_download_handler);
+
+        route_action(&Action::Upload(String::from("file.txt")), &file_system, &e);
+
+        assert_eq!(*mock_create_handler.called.borrow(), true);
+        assert_eq!(*mock_remove_handler.called.borrow(), false);
+        assert_eq!(*mock_download_handler.called.borrow(), false);
+    }
+
+    #[test]
+    fn test_delete_remote_action_calls_remove_handler() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let mock_create_handler = MockPathEventHandler::new();
+        let mock_remove_handler = MockPathEventHandler::new();
+        let mock_download_handler = MockPathEventHandler::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        e.add("create", &mock_create_handler);
+        e.add("remove", &mock_remove_handler);
+        e.add("download", &mock_download_handler);
+
+        route_action(
+            &Action::DeleteRemote(String::from("file.txt")),
+            &file_system,
+            &e,
+        );
+
+        assert_eq!(*mock_create_handler.called.borrow(), false);
+        assert_eq!(*mock_remove_handler.called.borrow(), true);
+        assert_eq!(*mock_download_handler.called.borrow(), false);
+    }
+
+    #[test]
+    fn test_state_folder_event_does_not_call_event_handler() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let mock_create_handler = MockPathEventHandler::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        e.add("create", &mock_create_handler);
+
+        route_event(
+            &DebouncedEvent::Create(PathBuf::from("/bucket/.bucket/state.log")),
+            "/bucket",
+            &e,
+        );
+
+        assert_eq!(*mock_create_handler.called.borrow(), false);
+    }
+
+    #[test]
+    fn test_nested_state_folder_event_calls_event_handler() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let mock_create_handler = MockPathEventHandler::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        e.add("create", &mock_create_handler);
+
+        route_event(
+            &DebouncedEvent::Create(PathBuf::from("/bucket/sub/.bucket/file.txt")),
+            "/bucket",
+            &e,
+        );
+
+        assert_eq!(*mock_create_handler.called.borrow(), true);
+    }
+
+    #[test]
+    fn test_rename_event_calls_rename_handler() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let mock_create_handler = MockPathEventHandler::new();
+        let mock_remove_handler = MockPathEventHandler::new();
+        let mock_rename_handler = MockPathEventHandler::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        e.add("create", &mock_create_handler);
+        e.add("remove", &mock_remove_handler);
+        e.add_rename("rename", &mock_rename_handler);
+
+        route_event(
+            &DebouncedEvent::Rename(PathBuf::from("old.txt"), PathBuf::from("new.txt")),
+            "/bucket",
+            &e,
+        );
+
+        assert_eq!(*mock_create_handler.called.borrow(), false);
+        assert_eq!(*mock_remove_handler.called.borrow(), false);
+        assert_eq!(*mock_rename_handler.called.borrow(), true);
+    }
+
+    #[test]
+    fn test_rewatch_replaces_watch() {
+        let mut mock_watcher = MockWatcher::failing(0);
+
+        rewatch(&mut mock_watcher, "/bucket", Duration::from_millis(0));
+
+        assert_eq!(*mock_watcher.unwatch_calls.borrow(), 1);
+        assert_eq!(*mock_watcher.watch_calls.borrow(), 1);
+    }
+
+    #[test]
+    fn test_rewatch_retries_until_watch_succeeds() {
+        let mut mock_watcher = MockWatcher::failing(2);
+
+        rewatch(&mut mock_watcher, "/bucket", Duration::from_millis(0));
+
+        assert_eq!(*mock_watcher.watch_calls.borrow(), 3);
+    }
 }
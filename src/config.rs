@@ -0,0 +1,635 @@
+use super::azure_client::{AzureAccount, AzureAuth};
+use super::bucket::{Config, StorageBackend};
+use super::reconcile::ReconcilePolicy;
+use super::retry::RetryPolicy;
+use super::s3_storage::S3Config;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use url::Url;
+
+#[derive(Debug, Fail)]
+pub enum ConfigError {
+    #[fail(display = "Unable to read the config file {:?} - {}", _0, _1)]
+    UnreadableFile(PathBuf, io::Error),
+    #[fail(display = "The config file {:?} is not valid - {}", _0, _1)]
+    InvalidFile(PathBuf, toml::de::Error),
+    #[fail(
+        display = "Unknown argument {:?} - the only option is --config <file>",
+        _0
+    )]
+    UnknownArgument(String),
+    #[fail(display = "--config needs the path of a config file")]
+    MissingConfigPath,
+    #[fail(
+        display = "{} is not set - set it in the config file or the {} environment variable",
+        _0, _1
+    )]
+    MissingSetting(&'static str, &'static str),
+    #[fail(display = "{:?} is not a valid value for {} - {}", _1, _0, _2)]
+    InvalidSetting(&'static str, String, &'static str),
+    #[fail(display = "The root folder {:?} does not exist", _0)]
+    RootFolderMissing(String),
+    #[fail(display = "The root folder {:?} is not a folder", _0)]
+    RootFolderNotAFolder(String),
+    #[fail(display = "{:?} is not a valid container name - {}", _0, _1)]
+    InvalidContainerName(String, &'static str),
+    #[fail(
+        display = "No credentials for the storage account - set azure.master_key, azure.sas_token \
+                   or azure.connection_string in the config file, or the STORAGE_MASTER_KEY, \
+                   STORAGE_SAS_TOKEN or STORAGE_CONNECTION_STRING environment variable"
+    )]
+    MissingCredentials,
+    #[fail(display = "The storage account credentials are not valid - {}", _0)]
+    InvalidCredentials(String),
+    #[fail(display = "The S3 endpoint {:?} is not a valid URL - {}", _0, _1)]
+    InvalidS3Endpoint(String, String),
+    #[fail(
+        display = "The storage folder {:?} can't be inside the root folder, or contain it",
+        _0
+    )]
+    StorageFolderOverlapsRoot(String),
+}
+
+// The config file, where every setting can also be given by an environment
+// variable. Values from the environment take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    root_folder: Option<String>,
+    storage_backend: Option<String>,
+    container: Option<String>,
+    poll_interval: Option<u64>,
+    reconcile_policy: Option<String>,
+    block_size: Option<usize>,
+    retry_attempts: Option<u32>,
+    retry_max_delay: Option<u64>,
+    workers: Option<usize>,
+    #[serde(default)]
+    azure: AzureSection,
+    #[serde(default)]
+    s3: S3Section,
+    #[serde(default)]
+    local: LocalSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AzureSection {
+    account: Option<String>,
+    master_key: Option<String>,
+    sas_token: Option<String>,
+    connection_string: Option<String>,
+    endpoint: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct S3Section {
+    region: Option<String>,
+    endpoint: Option<String>,
+    access_key: Option<String>,
+    secret_key: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LocalSection {
+    folder: Option<String>,
+}
+
+impl ConfigFile {
+    fn read(path: &Path) -> Result<ConfigFile, ConfigError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ConfigError::UnreadableFile(path.to_path_buf(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::InvalidFile(path.to_path_buf(), e))
+    }
+
+    // each setting under the name of the environment variable for it
+    fn into_settings(self) -> BTreeMap<&'static str, String> {
+        let number = |n: Option<u64>| n.map(|n| n.to_string());
+        vec![
+            ("ROOT_FOLDER", self.root_folder),
+            ("STORAGE_BACKEND", self.storage_backend),
+            ("STORAGE_CONTAINER", self.container),
+            ("POLL_INTERVAL", number(self.poll_interval)),
+            ("RECONCILE_POLICY", self.reconcile_policy),
+            ("BLOCK_SIZE", number(self.block_size.map(|s| s as u64))),
+            ("RETRY_ATTEMPTS", number(self.retry_attempts.map(u64::from))),
+            ("RETRY_MAX_DELAY", number(self.retry_max_delay)),
+            ("WORKERS", number(self.workers.map(|w| w as u64))),
+            ("STORAGE_ACCOUNT", self.azure.account),
+            ("STORAGE_MASTER_KEY", self.azure.master_key),
+            ("STORAGE_SAS_TOKEN", self.azure.sas_token),
+            ("STORAGE_CONNECTION_STRING", self.azure.connection_string),
+            ("STORAGE_ENDPOINT", self.azure.endpoint),
+            ("S3_REGION", self.s3.region),
+            ("S3_ENDPOINT", self.s3.endpoint),
+            ("S3_ACCESS_KEY", self.s3.access_key),
+            ("S3_SECRET_KEY", self.s3.secret_key),
+            ("STORAGE_FOLDER", self.local.folder),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.map(|v| (name, v)))
+        .collect()
+    }
+}
+
+// Settings from the config file, overridden by any set in the environment.
+struct Settings<'a> {
+    file: BTreeMap<&'static str, String>,
+    env: &'a Fn(&str) -> Option<String>,
+}
+
+impl<'a> Settings<'a> {
+    fn get(&self, name: &str) -> Option<String> {
+        (self.env)(name)
+            .or_else(|| self.file.get(name).cloned())
+            .filter(|v| !v.is_empty())
+    }
+
+    // `key` is where the setting goes in the config file
+    fn require(&self, key: &'static str, name: &'static str) -> Result<String, ConfigError> {
+        self.get(name).ok_or(ConfigError::MissingSetting(key, name))
+    }
+
+    fn number<T: FromStr + PartialOrd + Default>(
+        &self,
+        key: &'static str,
+        name: &'static str,
+        default: T,
+    ) -> Result<T, ConfigError> {
+        match self.get(name) {
+            None => Ok(default),
+            Some(value) => match value.parse() {
+                Ok(n) if n > T::default() => Ok(n),
+                _ => Err(ConfigError::InvalidSetting(
+                    key,
+                    value,
+                    "expected a whole number greater than 0",
+                )),
+            },
+        }
+    }
+}
+
+// bucket/config.toml in the usual folder for config on each platform
+pub fn default_config_path() -> Option<PathBuf> {
+    config_folder().map(|p| p.join("bucket").join("config.toml"))
+}
+
+#[cfg(windows)]
+fn config_folder() -> Option<PathBuf> {
+    env::var_os("APPDATA").map(PathBuf::from)
+}
+
+// XDG_CONFIG_HOME when it is set, otherwise ~/.config
+#[cfg(not(windows))]
+fn config_folder() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+}
+
+// The config file named with --config, if any.
+pub fn config_path(args: &[String]) -> Result<Option<PathBuf>, ConfigError> {
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            path = Some(args.next().ok_or(ConfigError::MissingConfigPath)?.into());
+        } else if arg.starts_with("--config=") {
+            path = Some(arg["--config=".len()..].into());
+        } else {
+            return Err(ConfigError::UnknownArgument(arg.clone()));
+        }
+    }
+    Ok(path)
+}
+
+// Reads the config file named on the command line, or the one in the default
+// location if there is one, and merges it with the environment.
+pub fn load(config_path: Option<PathBuf>) -> Result<Config, ConfigError> {
+    let file = match config_path {
+        Some(path) => ConfigFile::read(&path)?,
+        None => match default_config_path().filter(|p| p.is_file()) {
+            Some(path) => ConfigFile::read(&path)?,
+            None => ConfigFile::default(),
+        },
+    };
+
+    let config = build_config(file, &|name| env::var(name).ok())?;
+    validate(&config)?;
+    Ok(config)
+}
+
+fn build_config(file: ConfigFile, env: &Fn(&str) -> Option<String>) -> Result<Config, ConfigError> {
+    let settings = Settings {
+        file: file.into_settings(),
+        env,
+    };
+
+    let storage_backend = match settings.get("STORAGE_BACKEND") {
+        Some(ref b) if b == "s3" => {
+            let region = settings
+                .get("S3_REGION")
+                .unwrap_or_else(|| String::from("us-east-1"));
+            StorageBackend::S3(S3Config {
+                endpoint: settings
+                    .get("S3_ENDPOINT")
+                    .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region)),
+                region,
+                access_key: settings.require("s3.access_key", "S3_ACCESS_KEY")?,
+                secret_key: settings.require("s3.secret_key", "S3_SECRET_KEY")?,
+            })
+        }
+        Some(ref b) if b == "local" => {
+            StorageBackend::Local(settings.require("local.folder", "STORAGE_FOLDER")?)
+        }
+        Some(ref b) if b != "azure" => {
+            return Err(ConfigError::InvalidSetting(
+                "storage_backend",
+                b.clone(),
+                "expected azure, s3 or local",
+            ))
+        }
+        _ => StorageBackend::Azure,
+    };
+
+    // a connection string names the account itself, and a SAS token saves
+    // handing out the master key
+    let azure_auth = match (
+        settings.get("STORAGE_CONNECTION_STRING"),
+        settings.get("STORAGE_SAS_TOKEN"),
+        settings.get("STORAGE_MASTER_KEY"),
+    ) {
+        (Some(c), _, _) => AzureAuth::ConnectionString(c),
+        (None, Some(t), _) => AzureAuth::SasToken(t),
+        (None, None, Some(k)) => AzureAuth::SharedKey(k),
+        (None, None, None) => match storage_backend {
+            StorageBackend::Azure => return Err(ConfigError::MissingCredentials),
+            _ => AzureAuth::SharedKey(String::new()),
+        },
+    };
+    // the Azure account is only needed when storing blobs in Azure
+    let storage_account = match (&storage_backend, &azure_auth) {
+        (StorageBackend::Azure, AzureAuth::SharedKey(_))
+        | (StorageBackend::Azure, AzureAuth::SasToken(_)) => {
+            settings.require("azure.account", "STORAGE_ACCOUNT")?
+        }
+        _ => settings.get("STORAGE_ACCOUNT").unwrap_or_default(),
+    };
+    // and a local folder has no container or bucket
+    let root_container_name = match storage_backend {
+        StorageBackend::Local(_) => settings.get("STORAGE_CONTAINER").unwrap_or_default(),
+        _ => settings.require("container", "STORAGE_CONTAINER")?,
+    };
+
+    Ok(Config {
+        root_folder: settings.require("root_folder", "ROOT_FOLDER")?,
+        storage_account,
+        azure_auth,
+        root_container_name,
+        blob_endpoint: settings.get("STORAGE_ENDPOINT"),
+        poll_interval: Duration::from_secs(settings.number(
+            "poll_interval",
+            "POLL_INTERVAL",
+            60,
+        )?),
+        reconcile_policy: match settings.get("RECONCILE_POLICY") {
+            Some(ref p) if p == "mirror" => ReconcilePolicy::Mirror,
+            Some(ref p) if p != "download" => {
+                return Err(ConfigError::InvalidSetting(
+                    "reconcile_policy",
+                    p.clone(),
+                    "expected download or mirror",
+                ))
+            }
+            _ => ReconcilePolicy::Download,
+        },
+        block_size: settings.number("block_size", "BLOCK_SIZE", 4 * 1024 * 1024)?,
+        retry_policy: RetryPolicy {
+            max_attempts: settings.number("retry_attempts", "RETRY_ATTEMPTS", 5)?,
+            max_delay: Duration::from_secs(settings.number(
+                "retry_max_delay",
+                "RETRY_MAX_DELAY",
+                60,
+            )?),
+            ..RetryPolicy::default()
+        },
+        workers: settings.number("workers", "WORKERS", 4)?,
+        storage_backend,
+    })
+}
+
+fn validate(config: &Config) -> Result<(), ConfigError> {
+    let root_folder = Path::new(&config.root_folder);
+    if !root_folder.exists() {
+        return Err(ConfigError::RootFolderMissing(config.root_folder.clone()));
+    }
+    if !root_folder.is_dir() {
+        return Err(ConfigError::RootFolderNotAFolder(
+            config.root_folder.clone(),
+        ));
+    }
+
+    match config.storage_backend {
+        StorageBackend::Azure => {
+            validate_container_name(&config.root_container_name)?;
+            AzureAccount::new(
+                &config.storage_account,
+                config.blob_endpoint.as_ref().map(|e| e.as_str()),
+                &config.azure_auth,
+            )
+            .map_err(|e| ConfigError::InvalidCredentials(e.to_string()))?;
+        }
+        StorageBackend::S3(ref s3) => validate_s3_endpoint(&s3.endpoint)?,
+        StorageBackend::Local(ref folder) => validate_storage_folder(folder, root_folder)?,
+    }
+    Ok(())
+}
+
+fn validate_s3_endpoint(endpoint: &str) -> Result<(), ConfigError> {
+    let invalid = |reason: &str| {
+        Err(ConfigError::InvalidS3Endpoint(
+            String::from(endpoint),
+            String::from(reason),
+        ))
+    };
+    match Url::parse(endpoint) {
+        Ok(ref url) if url.host_str().is_none() => invalid("it has no host"),
+        Ok(_) => Ok(()),
+        Err(e) => invalid(&e.to_string()),
+    }
+}
+
+// syncing into the folder being synced would upload every file forever
+fn validate_storage_folder(folder: &str, root_folder: &Path) -> Result<(), ConfigError> {
+    let storage_folder = resolve_path(Path::new(folder));
+    let root_folder = resolve_path(root_folder);
+    if storage_folder.starts_with(&root_folder) || root_folder.starts_with(&storage_folder) {
+        return Err(ConfigError::StorageFolderOverlapsRoot(String::from(folder)));
+    }
+    Ok(())
+}
+
+// the storage folder is only created when bucket starts, so just the part of
+// it that already exists can have its links followed
+fn resolve_path(path: &Path) -> PathBuf {
+    if path.is_relative() {
+        if let Ok(current) = env::current_dir() {
+            return resolve_path(&current.join(path));
+        }
+    }
+    match path.canonicalize() {
+        Ok(resolved) => resolved,
+        Err(_) => match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => resolve_path(parent).join(name),
+            _ => path.to_path_buf(),
+        },
+    }
+}
+
+// https://docs.microsoft.com/rest/api/storageservices/naming-and-referencing-containers--blobs--and-metadata
+fn validate_container_name(name: &str) -> Result<(), ConfigError> {
+    let invalid = |reason| {
+        Err(ConfigError::InvalidContainerName(
+            String::from(name),
+            reason,
+        ))
+    };
+
+    if name.len() < 3 || name.len() > 63 {
+        return invalid("it must be from 3 to 63 characters long");
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        return invalid("it can only contain lowercase letters, numbers and hyphens");
+    }
+    if name.starts_with('-') || name.ends_with('-') {
+        return invalid("it must start and end with a letter or number");
+    }
+    if name.contains("--") {
+        return invalid("hyphens can't be next to each other");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> ConfigFile {
+        toml::from_str(contents).unwrap()
+    }
+
+    fn config(contents: &str, env: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let env: BTreeMap<String, String> = env
+            .iter()
+            .map(|&(k, v)| (String::from(k), String::from(v)))
+            .collect();
+        build_config(parse(contents), &|name| env.get(name).cloned())
+    }
+
+    const AZURE_CONFIG: &str = r#"
+        root_folder = "/home/me/bucket"
+        container = "files"
+        workers = 8
+
+        [azure]
+        account = "account"
+        master_key = "a2V5"
+    "#;
+
+    #[test]
+    fn test_config_file_is_read() {
+        let config = config(AZURE_CONFIG, &[]).unwrap();
+
+        assert_eq!(config.root_folder, "/home/me/bucket");
+        assert_eq!(config.storage_backend, StorageBackend::Azure);
+        assert_eq!(config.storage_account, "account");
+        assert_eq!(
+            config.azure_auth,
+            AzureAuth::SharedKey(String::from("a2V5"))
+        );
+        assert_eq!(config.root_container_name, "files");
+        assert_eq!(config.workers, 8);
+        assert_eq!(config.block_size, 4 * 1024 * 1024);
+        assert_eq!(config.poll_interval, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_environment_overrides_config_file() {
+        let config = config(
+            AZURE_CONFIG,
+            &[("STORAGE_CONTAINER", "other"), ("WORKERS", "2")],
+        )
+        .unwrap();
+
+        assert_eq!(config.root_container_name, "other");
+        assert_eq!(config.workers, 2);
+    }
+
+    #[test]
+    fn test_environment_alone_is_enough() {
+        let config = config(
+            "",
+            &[
+                ("ROOT_FOLDER", "/home/me/bucket"),
+                ("STORAGE_BACKEND", "local"),
+                ("STORAGE_FOLDER", "/mnt/share"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.storage_backend,
+            StorageBackend::Local(String::from("/mnt/share"))
+        );
+    }
+
+    #[test]
+    fn test_sas_token_is_preferred_to_master_key() {
+        let config = config(AZURE_CONFIG, &[("STORAGE_SAS_TOKEN", "sv=1&sig=2")]).unwrap();
+
+        assert_eq!(
+            config.azure_auth,
+            AzureAuth::SasToken(String::from("sv=1&sig=2"))
+        );
+    }
+
+    #[test]
+    fn test_missing_setting_names_file_key_and_variable() {
+        let error = config(
+            "container = \"files\"",
+            &[("STORAGE_ACCOUNT", "a"), ("STORAGE_MASTER_KEY", "a2V5")],
+        )
+        .err()
+        .unwrap();
+
+        assert_eq!(
+            error.to_string(),
+            "root_folder is not set - set it in the config file or the ROOT_FOLDER environment variable"
+        );
+    }
+
+    #[test]
+    fn test_missing_credentials_are_reported() {
+        match config("root_folder = \"/\"\ncontainer = \"files\"", &[]) {
+            Err(ConfigError::MissingCredentials) => (),
+            r => panic!("expected MissingCredentials, got {:?}", r.err()),
+        }
+    }
+
+    #[test]
+    fn test_invalid_numbers_are_reported() {
+        match config(AZURE_CONFIG, &[("WORKERS", "0")]) {
+            Err(ConfigError::InvalidSetting("workers", ref v, _)) if v == "0" => (),
+            r => panic!("expected InvalidSetting, got {:?}", r.err()),
+        }
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        assert!(toml::from_str::<ConfigFile>("root_foldr = \"/\"").is_err());
+        assert!(toml::from_str::<ConfigFile>("[azure]\nkey = \"a2V5\"").is_err());
+    }
+
+    #[test]
+    fn test_container_names_follow_azure_rules() {
+        assert!(validate_container_name("my-files-2").is_ok());
+        assert!(validate_container_name("ab").is_err());
+        assert!(validate_container_name(&"a".repeat(64)).is_err());
+        assert!(validate_container_name("My-Files").is_err());
+        assert!(validate_container_name("-files").is_err());
+        assert!(validate_container_name("files-").is_err());
+        assert!(validate_container_name("my--files").is_err());
+    }
+
+    #[test]
+    fn test_root_folder_must_be_a_folder() {
+        let mut config = config(AZURE_CONFIG, &[]).unwrap();
+        let file = env::temp_dir().join(format!("bucket-config-test-{}", ::std::process::id()));
+        fs::write(&file, b"").unwrap();
+
+        config.root_folder = file.to_string_lossy().into_owned();
+        match validate(&config) {
+            Err(ConfigError::RootFolderNotAFolder(_)) => (),
+            r => panic!("expected RootFolderNotAFolder, got {:?}", r),
+        }
+
+        config.root_folder = file.join("missing").to_string_lossy().into_owned();
+        match validate(&config) {
+            Err(ConfigError::RootFolderMissing(_)) => (),
+            r => panic!("expected RootFolderMissing, got {:?}", r),
+        }
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_s3_endpoint_must_be_a_url() {
+        let mut config = config(AZURE_CONFIG, &[]).unwrap();
+        config.root_folder = env::temp_dir().to_string_lossy().into_owned();
+        let s3 = |endpoint: &str| {
+            StorageBackend::S3(S3Config {
+                endpoint: String::from(endpoint),
+                region: String::from("us-east-1"),
+                access_key: String::from("key"),
+                secret_key: String::from("secret"),
+            })
+        };
+
+        config.storage_backend = s3("http://localhost:9000");
+        assert!(validate(&config).is_ok());
+
+        config.storage_backend = s3("localhost:9000");
+        match validate(&config) {
+            Err(ConfigError::InvalidS3Endpoint(_, _)) => (),
+            r => panic!("expected InvalidS3Endpoint, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_storage_folder_cannot_overlap_root_folder() {
+        let root_folder =
+            env::temp_dir().join(format!("bucket-config-root-{}", ::std::process::id()));
+        fs::create_dir_all(&root_folder).unwrap();
+        let mut config = config(AZURE_CONFIG, &[]).unwrap();
+        config.root_folder = root_folder.to_string_lossy().into_owned();
+        let local = |folder: &Path| StorageBackend::Local(folder.to_string_lossy().into_owned());
+
+        config.storage_backend = local(&root_folder.join("share"));
+        match validate(&config) {
+            Err(ConfigError::StorageFolderOverlapsRoot(_)) => (),
+            r => panic!("expected StorageFolderOverlapsRoot, got {:?}", r),
+        }
+        config.storage_backend = local(root_folder.parent().unwrap());
+        assert!(validate(&config).is_err());
+        config.storage_backend = local(&root_folder.with_extension("share"));
+        assert!(validate(&config).is_ok());
+        fs::remove_dir_all(&root_folder).unwrap();
+    }
+
+    #[test]
+    fn test_config_path_comes_from_arguments() {
+        let args = |a: &[&str]| a.iter().map(|s| String::from(*s)).collect::<Vec<String>>();
+
+        assert_eq!(config_path(&args(&[])).unwrap(), None);
+        assert_eq!(
+            config_path(&args(&["--config", "/etc/bucket.toml"])).unwrap(),
+            Some(PathBuf::from("/etc/bucket.toml"))
+        );
+        assert_eq!(
+            config_path(&args(&["--config=bucket.toml"])).unwrap(),
+            Some(PathBuf::from("bucket.toml"))
+        );
+        assert!(config_path(&args(&["--config"])).is_err());
+        assert!(config_path(&args(&["--verbose"])).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use chrono::Local;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// The time is part of the name so conflicts on the same day are kept apart,
+// and a counter is added while the name is still taken
+pub fn conflicted_copy_path<F>(p: &Path, is_taken: F) -> PathBuf
+where
+    F: Fn(&PathBuf) -> bool,
+{
+    let date = Local::now().format("%Y-%m-%d %H%M%S").to_string();
+    let host = hostname();
+    (1..)
+        .map(|copy| conflicted_copy_name(p, &host, &date, copy))
+        .find(|candidate| !is_taken(candidate))
+        .unwrap()
+}
+
+pub fn conflicted_copy_name(p: &Path, host: &str, date: &str, copy: u32) -> PathBuf {
+    let stem = p
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let label = match copy {
+        1 => format!("conflicted copy {} {}", host, date),
+        _ => format!("conflicted copy {} {} {}", host, date, copy),
+    };
+    let file_name = match p.extension() {
+        Some(ext) => format!("{} ({}).{}", stem, label, ext.to_string_lossy()),
+        None => format!("{} ({})", stem, label),
+    };
+    p.with_file_name(file_name)
+}
+
+fn hostname() -> String {
+    env::var("HOSTNAME")
+        .or_else(|_| env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| {
+            fs::read_to_string("/etc/hostname")
+                .ok()
+                .map(|h| String::from(h.trim()))
+        })
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conflicted_copy_keeps_extension() {
+        let path = conflicted_copy_name(
+            Path::new("/bucket/sheets/budget.xlsx"),
+            "laptop",
+            "2018-11-01",
+            1,
+        );
+        assert_eq!(
+            PathBuf::from("/bucket/sheets/budget (conflicted copy laptop 2018-11-01).xlsx"),
+            path
+        );
+    }
+
+    #[test]
+    fn test_conflicted_copy_without_extension() {
+        let path = conflicted_copy_name(Path::new("/bucket/README"), "laptop", "2018-11-01", 1);
+        assert_eq!(
+            PathBuf::from("/bucket/README (conflicted copy laptop 2018-11-01)"),
+            path
+        );
+    }
+
+    #[test]
+    fn test_later_conflicted_copies_are_numbered() {
+        let path = conflicted_copy_name(
+            Path::new("/bucket/sheets/budget.xlsx"),
+            "laptop",
+            "2018-11-01 093000",
+            2,
+        );
+        assert_eq!(
+            PathBuf::from(
+                "/bucket/sheets/budget (conflicted copy laptop 2018-11-01 093000 2).xlsx"
+            ),
+            path
+        );
+    }
+
+    #[test]
+    fn test_conflicted_copy_path_skips_taken_names() {
+        let path = conflicted_copy_path(Path::new("/bucket/budget.xlsx"), |p| {
+            !p.to_string_lossy().ends_with(" 3).xlsx")
+        });
+
+        assert!(path.to_string_lossy().ends_with(" 3).xlsx"));
+    }
+}
@@ -1,23 +1,34 @@
+use super::conflict;
 use super::file_system;
 use super::storage;
+use super::sync_state::{self, Operation};
+use failure::err_msg;
+use sentry::integrations::failure::capture_error;
 use std::collections::HashMap;
 use std::path::PathBuf;
 
+const DELETE_ATTEMPTS: u32 = 3;
+
 pub struct EventHandler<'a> {
     event_handlers: HashMap<&'a str, &'a PathEventHandler>,
+    rename_handlers: HashMap<&'a str, &'a RenameEventHandler>,
     storage: &'a storage::Storage,
     file_system: &'a file_system::FileSystem,
+    sync_state: &'a sync_state::SyncState,
 }
 
 impl<'a> EventHandler<'a> {
     pub fn new(
         storage: &'a storage::Storage,
         file_system: &'a file_system::FileSystem,
+        sync_state: &'a sync_state::SyncState,
     ) -> EventHandler<'a> {
         EventHandler {
             event_handlers: HashMap::new(),
+            rename_handlers: HashMap::new(),
             storage,
             file_system,
+            sync_state,
         }
     }
 
@@ -25,10 +36,34 @@ impl<'a> EventHandler<'a> {
         self.event_handlers.insert(event_name, event_handler);
     }
 
-    pub fn call(&self, event_name: &str, path: &PathBuf) {
+    pub fn replay_queued(&self) {
+        replay_operations(self.storage, self.file_system, self.sync_state);
+    }
+
+    pub fn add_rename(&mut self, event_name: &'a str, event_handler: &'a RenameEventHandler) {
+        self.rename_handlers.insert(event_name, event_handler);
+    }
+}
+
+// Anything events can be sent to, either handled straight away or passed on
+// to a pool of workers.
+pub trait EventDispatcher {
+    fn call(&self, event_name: &str, path: &PathBuf);
+    fn call_rename(&self, event_name: &str, from: &PathBuf, to: &PathBuf);
+}
+
+impl<'a> EventDispatcher for EventHandler<'a> {
+    fn call(&self, event_name: &str, path: &PathBuf) {
         if let Some(f) = self.event_handlers.get(event_name) {
             trace!("Calling event for {}", event_name);
-            f.handle(path, self.storage, self.file_system);
+            f.handle(path, self.storage, self.file_system, self.sync_state);
+        }
+    }
+
+    fn call_rename(&self, event_name: &str, from: &PathBuf, to: &PathBuf) {
+        if let Some(f) = self.rename_handlers.get(event_name) {
+            trace!("Calling rename event for {}", event_name);
+            f.handle(from, to, self.storage, self.file_system, self.sync_state);
         }
     }
 }
@@ -39,6 +74,18 @@ pub trait PathEventHandler {
         path: &PathBuf,
         storage: &storage::Storage,
         file_system: &file_system::FileSystem,
+        sync_state: &sync_state::SyncState,
+    );
+}
+
+pub trait RenameEventHandler {
+    fn handle(
+        &self,
+        from: &PathBuf,
+        to: &PathBuf,
+        storage: &storage::Storage,
+        file_system: &file_system::FileSystem,
+        sync_state: &sync_state::SyncState,
     );
 }
 
@@ -50,15 +97,21 @@ impl PathEventHandler for CreatedEvent {
         path: &PathBuf,
         storage: &storage::Storage,
         file_system: &file_system::FileSystem,
+        sync_state: &sync_state::SyncState,
     ) {
-        if path.is_dir() {
+        if file_system.is_dir(path) {
             return;
         }
-        let blob_name = file_system.get_blob_name(path);
-        let file_content = file_system.get_file_contents(path);
-        if let Err(e) = storage.upload(&blob_name, file_content) {
-            trace!("Error uploading - {}", e);
-        }
+        let blob_name = match file_system.get_blob_name(path) {
+            Ok(blob_name) => blob_name,
+            Err(e) => return report_file_system_error(path, &e),
+        };
+        run_or_queue(
+            Operation::Upload(blob_name),
+            storage,
+            file_system,
+            sync_state,
+        );
     }
 }
 
@@ -70,21 +123,119 @@ impl PathEventHandler for RemovedEvent {
         path: &PathBuf,
         storage: &storage::Storage,
         file_system: &file_system::FileSystem,
+        sync_state: &sync_state::SyncState,
     ) {
-        let blob_name = file_system.get_blob_name(path);
+        let blob_name = match file_system.get_blob_name(path) {
+            Ok(blob_name) => blob_name,
+            Err(e) => return report_file_system_error(path, &e),
+        };
+        run_or_queue(
+            Operation::Delete(blob_name),
+            storage,
+            file_system,
+            sync_state,
+        );
+    }
+}
 
-        match storage.delete(&blob_name) {
-            Err(storage::StorageError::PathNotFound) => {
-                let blobs_to_delete = storage.list_folder_blobs(&blob_name).unwrap();
-                for blob in blobs_to_delete {
-                    if let Err(e) = storage.delete(&file_system.encode_file_name(&blob_name)) {
-                        trace!("Error deleting folder content - {}", e);
-                    }
+pub struct DownloadEvent {}
+
+impl PathEventHandler for DownloadEvent {
+    fn handle(
+        &self,
+        path: &PathBuf,
+        storage: &storage::Storage,
+        file_system: &file_system::FileSystem,
+        sync_state: &sync_state::SyncState,
+    ) {
+        let blob_name = match file_system.get_blob_name(path) {
+            Ok(blob_name) => blob_name,
+            Err(e) => return report_file_system_error(path, &e),
+        };
+
+        let unsynced_change =
+            match is_unsynced_change(path, &blob_name, storage, file_system, sync_state) {
+                Ok(unsynced_change) => unsynced_change,
+                Err(ref e) if !e.is_retryable() => {
+                    return report_storage_error("downloading", &blob_name, e)
                 }
-            }
-            Err(e) => trace!("Error deleting - {}", e),
-            Ok(_) => (),
+                Err(e) => return trace!("Error downloading - {}", e),
+            };
+        if unsynced_change || is_modified_since_sync(path, &blob_name, file_system, sync_state) {
+            resolve_conflict(path, &blob_name, storage, file_system, sync_state);
+        } else {
+            download_file(path, &blob_name, storage, file_system, sync_state);
+        }
+    }
+}
+
+pub struct RemoteRemovedEvent {}
+
+impl PathEventHandler for RemoteRemovedEvent {
+    fn handle(
+        &self,
+        path: &PathBuf,
+        storage: &storage::Storage,
+        file_system: &file_system::FileSystem,
+        sync_state: &sync_state::SyncState,
+    ) {
+        if file_system.is_dir(path) {
+            return;
+        }
+        let blob_name = match file_system.get_blob_name(path) {
+            Ok(blob_name) => blob_name,
+            Err(e) => return report_file_system_error(path, &e),
+        };
+
+        if is_modified_since_sync(path, &blob_name, file_system, sync_state) {
+            // the local edit wins over the remote delete
+            trace!(
+                "{:?} has local changes, uploading instead of removing",
+                path
+            );
+            sync_state.remove(&blob_name);
+            run_or_queue(
+                Operation::Upload(blob_name),
+                storage,
+                file_system,
+                sync_state,
+            );
+            return;
+        }
+
+        if let Err(e) = file_system.remove_file(path) {
+            report_file_system_error(path, &e);
+        }
+        sync_state.remove(&blob_name);
+    }
+}
+
+pub struct RenamedEvent {}
+
+impl RenameEventHandler for RenamedEvent {
+    fn handle(
+        &self,
+        from: &PathBuf,
+        to: &PathBuf,
+        storage: &storage::Storage,
+        file_system: &file_system::FileSystem,
+        sync_state: &sync_state::SyncState,
+    ) {
+        let (from_blob_name, to_blob_name) = match (
+            file_system.get_blob_name(from),
+            file_system.get_blob_name(to),
+        ) {
+            (Ok(from_blob_name), Ok(to_blob_name)) => (from_blob_name, to_blob_name),
+            (Err(e), _) => return report_file_system_error(from, &e),
+            (_, Err(e)) => return report_file_system_error(to, &e),
         };
+
+        run_or_queue(
+            Operation::Rename(from_blob_name, to_blob_name),
+            storage,
+            file_system,
+            sync_state,
+        );
     }
 }
 
@@ -94,64 +245,522 @@ impl PathEventHandler for UpdatedEvent {
     fn handle(
         &self,
         path: &PathBuf,
-        _storage: &storage::Storage,
-        _file_system: &file_system::FileSystem,
+        storage: &storage::Storage,
+        file_system: &file_system::FileSystem,
+        sync_state: &sync_state::SyncState,
     ) {
-        trace!("Called UpdatedEvent with {:?}", path);
+        if file_system.is_dir(path) {
+            return;
+        }
+        let blob_name = match file_system.get_blob_name(path) {
+            Ok(blob_name) => blob_name,
+            Err(e) => return report_file_system_error(path, &e),
+        };
+        run_or_queue(
+            Operation::Upload(blob_name),
+            storage,
+            file_system,
+            sync_state,
+        );
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::cell::RefCell;
+// While earlier operations on the same blobs are still waiting for storage to
+// come back, new ones queue up behind them so they are applied in the order
+// they happened. Operations on other blobs go ahead.
+fn run_or_queue(
+    operation: Operation,
+    storage: &storage::Storage,
+    file_system: &file_system::FileSystem,
+    sync_state: &sync_state::SyncState,
+) {
+    if sync_state.has_pending_operations_on(&operation) {
+        trace!("Queueing {:?} behind pending operations", operation);
+        return sync_state.enqueue(operation);
+    }
+    match run_operation(&operation, storage, file_system, sync_state) {
+        Err(ref e) if e.is_retryable() => {
+            trace!(
+                "Queueing {:?} until storage is available - {}",
+                operation,
+                e
+            );
+            sync_state.enqueue(operation);
+        }
+        Err(e) => {
+            capture_error(&err_msg(format!("Error running {:?} - {}", operation, e)));
+        }
+        Ok(_) => (),
+    }
+}
 
-    struct MockStorage {
-        upload_called: RefCell<bool>,
-        download_called: RefCell<bool>,
-        delete_called: RefCell<bool>,
-        list_folder_blobs_called: RefCell<bool>,
-        return_path_not_found_error: RefCell<bool>,
-    }
-
-    impl MockStorage {
-        fn new() -> MockStorage {
-            MockStorage {
-                upload_called: RefCell::new(false),
-                download_called: RefCell::new(false),
-                delete_called: RefCell::new(false),
-                list_folder_blobs_called: RefCell::new(false),
-                return_path_not_found_error: RefCell::new(false),
+// An operation that keeps failing only holds back later operations on the
+// same blobs, everything else in the queue is still replayed.
+fn replay_operations(
+    storage: &storage::Storage,
+    file_system: &file_system::FileSystem,
+    sync_state: &sync_state::SyncState,
+) {
+    let mut blocked: Vec<Operation> = Vec::new();
+    for operation in sync_state.pending_operations() {
+        if blocked.iter().any(|b| b.overlaps(&operation)) {
+            blocked.push(operation);
+            continue;
+        }
+        match run_operation(&operation, storage, file_system, sync_state) {
+            // nothing else will get through while storage can't be reached
+            Err(ref e @ storage::StorageError::ConnectionError(_)) => {
+                trace!("Storage is still unavailable - {}", e);
+                return;
+            }
+            Err(ref e) if e.is_retryable() => {
+                trace!("Replaying {:?} failed again - {}", operation, e);
+                blocked.push(operation);
             }
+            Err(e) => {
+                capture_error(&err_msg(format!("Error running {:?} - {}", operation, e)));
+                sync_state.complete_operation(&operation);
+            }
+            Ok(_) => sync_state.complete_operation(&operation),
+        }
+    }
+}
+
+// Only failures worth trying again later are returned, anything else is
+// dealt with here.
+fn run_operation(
+    operation: &Operation,
+    storage: &storage::Storage,
+    file_system: &file_system::FileSystem,
+    sync_state: &sync_state::SyncState,
+) -> Result<(), storage::StorageError> {
+    match operation {
+        Operation::Upload(blob_name) => match file_system.get_file_path(blob_name) {
+            Ok(path) => upload_file(&path, blob_name, storage, file_system, sync_state),
+            Err(e) => {
+                trace!("Error uploading - {}", e);
+                capture_error(&err_msg(format!("Error uploading {} - {}", blob_name, e)));
+                Ok(())
+            }
+        },
+        Operation::Delete(blob_name) => delete_path(blob_name, storage, file_system, sync_state),
+        Operation::Rename(from_blob_name, to_blob_name) => rename_path(
+            from_blob_name,
+            to_blob_name,
+            storage,
+            file_system,
+            sync_state,
+        ),
+    }
+}
+
+fn upload_file(
+    path: &PathBuf,
+    blob_name: &str,
+    storage: &storage::Storage,
+    file_system: &file_system::FileSystem,
+    sync_state: &sync_state::SyncState,
+) -> Result<(), storage::StorageError> {
+    let hash = match file_system.get_file_hash(path) {
+        Ok(hash) => hash,
+        Err(e) => {
+            report_file_system_error(path, &e);
+            return Ok(());
         }
+    };
 
-        fn set_return_path_not_found_error(&self, f: bool) {
-            *self.return_path_not_found_error.borrow_mut() = f;
+    let entry = sync_state.get(blob_name);
+    if let Some(ref entry) = entry {
+        if entry.hash == hash {
+            trace!("{:?} is unchanged since the last sync", path);
+            return Ok(());
         }
     }
 
-    impl storage::Storage for MockStorage {
-        fn upload(&self, blob_name: &str, data: Vec<u8>) -> Result<(), storage::StorageError> {
-            *self.upload_called.borrow_mut() = true;
-            Ok(())
+    let (mut content, len) = match open_for_upload(path, file_system) {
+        Ok(opened) => opened,
+        Err(e) => {
+            report_file_system_error(path, &e);
+            return Ok(());
         }
-        fn download(&self, p: &PathBuf) {
-            *self.download_called.borrow_mut() = true;
+    };
+    let result = match entry {
+        Some(ref entry) => storage.upload_if_match(blob_name, &mut content, len, &entry.etag),
+        // someone else may have created the blob since it was last listed
+        None => storage.upload_if_absent(blob_name, &mut content, len),
+    };
+
+    match result {
+        Ok(etag) => record_sync(path, blob_name, hash, etag, file_system, sync_state),
+        Err(storage::StorageError::ConditionNotMet) => {
+            resolve_conflict(path, blob_name, storage, file_system, sync_state)
         }
-        fn delete(&self, blob_name: &str) -> Result<(), storage::StorageError> {
-            *self.delete_called.borrow_mut() = true;
+        Err(ref e) if !e.is_retryable() => report_storage_error("uploading", blob_name, e),
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
 
-            if *self.return_path_not_found_error.borrow() {
-                return Err(storage::StorageError::PathNotFound);
+fn delete_path(
+    blob_name: &str,
+    storage: &storage::Storage,
+    file_system: &file_system::FileSystem,
+    sync_state: &sync_state::SyncState,
+) -> Result<(), storage::StorageError> {
+    match storage.delete(blob_name) {
+        Err(storage::StorageError::PathNotFound) => {
+            sync_state.remove(blob_name);
+            // the folder can only be deleted once its blobs can be listed
+            match delete_folder(blob_name, storage, file_system, sync_state) {
+                Ok(failed) => {
+                    for (blob, e) in failed {
+                        capture_error(&err_msg(format!("Error deleting {} - {}", blob, e)));
+                    }
+                }
+                Err(ref e) if !e.is_retryable() => {
+                    capture_error(&err_msg(format!("Error listing {} - {}", blob_name, e)))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(ref e) if !e.is_retryable() => report_storage_error("deleting", blob_name, e),
+        Err(e) => return Err(e),
+        Ok(_) => sync_state.remove(blob_name),
+    };
+    Ok(())
+}
+
+fn rename_path(
+    from_blob_name: &str,
+    to_blob_name: &str,
+    storage: &storage::Storage,
+    file_system: &file_system::FileSystem,
+    sync_state: &sync_state::SyncState,
+) -> Result<(), storage::StorageError> {
+    let to = match file_system.get_file_path(to_blob_name) {
+        Ok(to) => to,
+        Err(e) => {
+            trace!("Error renaming - {}", e);
+            capture_error(&err_msg(format!(
+                "Error renaming {} - {}",
+                from_blob_name, e
+            )));
+            return Ok(());
+        }
+    };
+
+    if !file_system.is_dir(&to) {
+        let etag = match storage.copy(from_blob_name, to_blob_name) {
+            Ok(etag) => etag,
+            Err(storage::StorageError::PathNotFound) => {
+                return upload_file(&to, to_blob_name, storage, file_system, sync_state)
             }
+            Err(ref e) if !e.is_retryable() => {
+                report_storage_error("copying", from_blob_name, e);
+                upload_file(&to, to_blob_name, storage, file_system, sync_state)?;
+                // the old blob is only removed once the file is stored under its new name
+                if sync_state.get(to_blob_name).is_some() {
+                    match delete_blob(from_blob_name, storage, sync_state) {
+                        Err(ref e) if !e.is_retryable() => {
+                            report_storage_error("deleting", from_blob_name, e)
+                        }
+                        result => return result,
+                    }
+                }
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        match finish_move(from_blob_name, to_blob_name, etag, storage, sync_state) {
+            Err(ref e) if !e.is_retryable() => report_storage_error("renaming", from_blob_name, e),
+            Err(e) => return Err(e),
+            Ok(_) => (),
+        }
+        return Ok(());
+    }
 
+    for blob in storage::BlobListing::folder(storage, from_blob_name) {
+        let blob_name = match blob {
+            Ok(blob) => file_system.encode_file_name(&blob.name),
+            Err(ref e) if !e.is_retryable() => {
+                report_storage_error("listing", from_blob_name, e);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let new_blob_name = format!("{}{}", to_blob_name, &blob_name[from_blob_name.len()..]);
+        match move_blob(&blob_name, &new_blob_name, storage, sync_state) {
+            Err(ref e) if !e.is_retryable() => report_storage_error("renaming", &blob_name, e),
+            Err(e) => return Err(e),
+            Ok(_) => (),
+        }
+    }
+    Ok(())
+}
+
+fn open_for_upload(
+    path: &PathBuf,
+    file_system: &file_system::FileSystem,
+) -> Result<(Box<file_system::FileContent>, u64), file_system::FileSystemError> {
+    let len = file_system.get_file_info(path)?.size;
+    Ok((file_system.open_file(path)?, len))
+}
+
+// a single unreadable or oddly named file is reported rather than stopping sync
+fn report_file_system_error(path: &PathBuf, e: &file_system::FileSystemError) {
+    trace!("Error reading {:?} - {}", path, e);
+    capture_error(&err_msg(format!("Error reading {:?} - {}", path, e)));
+}
+
+// nothing tries these again, so they are reported rather than only traced
+fn report_storage_error(action: &str, blob_name: &str, e: &storage::StorageError) {
+    trace!("Error {} {} - {}", action, blob_name, e);
+    capture_error(&err_msg(format!("Error {} {} - {}", action, blob_name, e)));
+}
+
+fn delete_folder(
+    folder_name: &str,
+    storage: &storage::Storage,
+    file_system: &file_system::FileSystem,
+    sync_state: &sync_state::SyncState,
+) -> Result<Vec<(String, storage::StorageError)>, storage::StorageError> {
+    let mut failed = Vec::new();
+
+    for blob in storage::BlobListing::folder(storage, folder_name) {
+        let blob_name = file_system.encode_file_name(&blob?.name);
+        if let Err(e) = delete_blob(&blob_name, storage, sync_state) {
+            trace!("Error deleting {} - {}", blob_name, e);
+            failed.push((blob_name, e));
+        }
+    }
+
+    // only the blobs that failed are kept around for another attempt
+    for attempt in 2..=DELETE_ATTEMPTS {
+        if failed.is_empty() {
+            break;
+        }
+        let mut still_failed = Vec::new();
+        for (blob_name, _) in failed {
+            if let Err(e) = delete_blob(&blob_name, storage, sync_state) {
+                trace!(
+                    "Error deleting {} on attempt {} - {}",
+                    blob_name,
+                    attempt,
+                    e
+                );
+                still_failed.push((blob_name, e));
+            }
+        }
+        failed = still_failed;
+    }
+
+    Ok(failed)
+}
+
+fn delete_blob(
+    blob_name: &str,
+    storage: &storage::Storage,
+    sync_state: &sync_state::SyncState,
+) -> Result<(), storage::StorageError> {
+    match storage.delete(blob_name) {
+        Ok(_) | Err(storage::StorageError::PathNotFound) => {
+            sync_state.remove(blob_name);
             Ok(())
         }
-        fn list_folder_blobs(&self, blob_name: &str) -> Result<Vec<String>, storage::StorageError> {
-            *self.list_folder_blobs_called.borrow_mut() = true;
-            Ok(Vec::new())
+        Err(e) => Err(e),
+    }
+}
+
+fn move_blob(
+    from_blob_name: &str,
+    to_blob_name: &str,
+    storage: &storage::Storage,
+    sync_state: &sync_state::SyncState,
+) -> Result<(), storage::StorageError> {
+    let etag = storage.copy(from_blob_name, to_blob_name)?;
+    finish_move(from_blob_name, to_blob_name, etag, storage, sync_state)
+}
+
+fn finish_move(
+    from_blob_name: &str,
+    to_blob_name: &str,
+    etag: String,
+    storage: &storage::Storage,
+    sync_state: &sync_state::SyncState,
+) -> Result<(), storage::StorageError> {
+    storage.delete(from_blob_name)?;
+
+    if let Some(entry) = sync_state.get(from_blob_name) {
+        sync_state.set(to_blob_name, sync_state::SyncEntry { etag, ..entry });
+        sync_state.remove(from_blob_name);
+    }
+    Ok(())
+}
+
+fn download_file(
+    path: &PathBuf,
+    blob_name: &str,
+    storage: &storage::Storage,
+    file_system: &file_system::FileSystem,
+    sync_state: &sync_state::SyncState,
+) {
+    let blob = match storage.download(blob_name) {
+        Ok(blob) => blob,
+        Err(ref e) if !e.is_retryable() => {
+            return report_storage_error("downloading", blob_name, e)
+        }
+        Err(e) => {
+            trace!("Error downloading - {}", e);
+            return;
+        }
+    };
+
+    match sync_state.get(blob_name) {
+        Some(ref entry) if entry.etag == blob.etag && file_system.is_file(path) => {
+            trace!("{:?} is already up to date", path);
+            return;
         }
+        _ => {}
+    }
+
+    let mut content = sync_state::HashingReader::new(blob.content);
+    if let Err(e) = file_system.write_file(path, &mut content) {
+        return report_file_system_error(path, &e);
+    }
+
+    record_sync(
+        path,
+        blob_name,
+        content.hash(),
+        blob.etag,
+        file_system,
+        sync_state,
+    );
+}
+
+// Both sides changed since the last sync. The remote version keeps the original
+// name and the local changes are kept alongside it as a conflicted copy.
+fn resolve_conflict(
+    path: &PathBuf,
+    blob_name: &str,
+    storage: &storage::Storage,
+    file_system: &file_system::FileSystem,
+    sync_state: &sync_state::SyncState,
+) {
+    let conflict_path = conflict::conflicted_copy_path(path, |candidate| {
+        file_system.is_file(candidate)
+            || file_system
+                .get_blob_name(candidate)
+                .map(|b| sync_state.get(&b).is_some())
+                .unwrap_or(false)
+    });
+    trace!(
+        "Conflict on {:?}, keeping local copy as {:?}",
+        path,
+        conflict_path
+    );
+
+    let mut local_content = match file_system.open_file(path) {
+        Ok(content) => sync_state::HashingReader::new(content),
+        Err(e) => return report_file_system_error(path, &e),
+    };
+    if let Err(e) = file_system.write_file(&conflict_path, &mut local_content) {
+        return report_file_system_error(&conflict_path, &e);
+    }
+
+    let hash = local_content.hash();
+    let upload = file_system
+        .get_blob_name(&conflict_path)
+        .and_then(|conflict_blob_name| {
+            open_for_upload(&conflict_path, file_system)
+                .map(|(content, len)| (conflict_blob_name, content, len))
+        });
+    let (conflict_blob_name, mut content, len) = match upload {
+        Ok(upload) => upload,
+        Err(e) => return report_file_system_error(&conflict_path, &e),
+    };
+    // an existing conflicted copy is never overwritten
+    match storage.upload_if_absent(&conflict_blob_name, &mut content, len) {
+        Ok(etag) => record_sync(
+            &conflict_path,
+            &conflict_blob_name,
+            hash,
+            etag,
+            file_system,
+            sync_state,
+        ),
+        // the conflicted copy isn't tried again, so every failure is reported
+        Err(ref e) => report_storage_error("uploading conflicted copy", &conflict_blob_name, e),
+    }
+
+    sync_state.remove(blob_name);
+    download_file(path, blob_name, storage, file_system, sync_state);
+}
+
+fn is_modified_since_sync(
+    path: &PathBuf,
+    blob_name: &str,
+    file_system: &file_system::FileSystem,
+    sync_state: &sync_state::SyncState,
+) -> bool {
+    match sync_state.get(blob_name) {
+        Some(ref entry) if file_system.is_file(path) => match file_system.get_file_hash(path) {
+            Ok(hash) => hash != entry.hash,
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+// A local file that was never synced is only replaced when it already holds
+// the blob's content.
+fn is_unsynced_change(
+    path: &PathBuf,
+    blob_name: &str,
+    storage: &storage::Storage,
+    file_system: &file_system::FileSystem,
+    sync_state: &sync_state::SyncState,
+) -> Result<bool, storage::StorageError> {
+    if sync_state.get(blob_name).is_some() || !file_system.is_file(path) {
+        return Ok(false);
     }
+    let local_hash = match file_system.get_file_hash(path) {
+        Ok(hash) => hash,
+        Err(_) => return Ok(false),
+    };
+    let blob = storage.download(blob_name)?;
+    Ok(sync_state::hash_reader(blob.content)? != local_hash)
+}
+
+fn record_sync(
+    path: &PathBuf,
+    blob_name: &str,
+    hash: String,
+    etag: String,
+    file_system: &file_system::FileSystem,
+    sync_state: &sync_state::SyncState,
+) {
+    let (local_modified, local_size) = match file_system.get_file_info(path) {
+        Ok(info) => (info.last_modified.timestamp(), info.size),
+        Err(_) => (0, 0),
+    };
+    sync_state.set(
+        blob_name,
+        sync_state::SyncEntry {
+            local_modified,
+            local_size,
+            hash,
+            etag,
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use file_system::FileSystem;
+    use std::cell::RefCell;
+    use test_support::{MemoryFileSystem, MemoryStorage};
 
     struct MockPathEventHandler {
         called: RefCell<bool>,
@@ -171,48 +780,31 @@ mod tests {
             _path: &PathBuf,
             _storage: &storage::Storage,
             _file_system: &file_system::FileSystem,
+            _sync_state: &sync_state::SyncState,
         ) {
             *self.called.borrow_mut() = true;
         }
     }
 
-    struct MockFileSystem {
-        get_blob_name_called: RefCell<bool>,
-        get_file_contents_called: RefCell<bool>,
-        encode_file_name_called: RefCell<bool>,
-    }
-
-    impl MockFileSystem {
-        fn new() -> MockFileSystem {
-            MockFileSystem {
-                get_blob_name_called: RefCell::new(false),
-                get_file_contents_called: RefCell::new(false),
-                encode_file_name_called: RefCell::new(false),
-            }
-        }
-    }
-
-    impl file_system::FileSystem for MockFileSystem {
-        fn get_blob_name(&self, p: &PathBuf) -> String {
-            *self.get_blob_name_called.borrow_mut() = true;
-            String::from("")
-        }
-        fn get_file_contents(&self, p: &PathBuf) -> Vec<u8> {
-            *self.get_file_contents_called.borrow_mut() = true;
-            Vec::new()
-        }
-        fn encode_file_name(&self, f: &str) -> String {
-            *self.encode_file_name_called.borrow_mut() = true;
-            String::from("")
-        }
+    fn synced(sync_state: &sync_state::SyncState, blob_name: &str, data: &[u8], etag: &str) {
+        sync_state.set(
+            blob_name,
+            sync_state::SyncEntry {
+                local_modified: 0,
+                local_size: data.len() as u64,
+                hash: sync_state::hash(data),
+                etag: String::from(etag),
+            },
+        );
     }
 
     #[test]
     fn test_handler_added_to_list() {
-        let mock_file_system = MockFileSystem::new();
-        let mock_storage = MockStorage::new();
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
         let mock_event_handler = MockPathEventHandler::new();
-        let mut e = EventHandler::new(&mock_storage, &mock_file_system);
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
 
         e.add("mock", &mock_event_handler);
 
@@ -221,10 +813,11 @@ mod tests {
 
     #[test]
     fn test_handler_is_called() {
-        let mock_file_system = MockFileSystem::new();
-        let mock_storage = MockStorage::new();
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
         let mock_event_handler = MockPathEventHandler::new();
-        let mut e = EventHandler::new(&mock_storage, &mock_file_system);
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
 
         e.add("mock", &mock_event_handler);
         e.call("mock", &PathBuf::new());
@@ -233,51 +826,772 @@ mod tests {
     }
 
     #[test]
-    fn test_create_event_calls_storage_upload() {
-        let mock_file_system = MockFileSystem::new();
-        let mock_storage = MockStorage::new();
-        let mut e = EventHandler::new(&mock_storage, &mock_file_system);
+    fn test_create_event_uploads_file() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        let path = file_system.put("file.txt", b"hello");
 
         e.add("create", &CreatedEvent {});
-        e.call("create", &PathBuf::new());
+        e.call("create", &path);
 
-        assert!(*mock_storage.upload_called.borrow());
+        assert_eq!(storage.contents("file.txt"), Some(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn test_update_event_uploads_file() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        let path = file_system.put("file.txt", b"hello");
+
+        e.add("update", &UpdatedEvent {});
+        e.call("update", &path);
+
+        assert_eq!(storage.contents("file.txt"), Some(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn test_update_event_skips_upload_when_content_unchanged() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        synced(&sync_state, "file.txt", b"hello", "etag");
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        let path = file_system.put("file.txt", b"hello");
+
+        e.add("update", &UpdatedEvent {});
+        e.call("update", &path);
+
+        assert!(storage.blob_names().is_empty());
+    }
+
+    #[test]
+    fn test_update_event_uploads_changed_content_with_previous_etag() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let previous = storage.put("file.txt", b"previous");
+        synced(&sync_state, "file.txt", b"previous", &previous);
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        let path = file_system.put("file.txt", b"changed");
+
+        e.add("update", &UpdatedEvent {});
+        e.call("update", &path);
+
+        assert_eq!(storage.contents("file.txt"), Some(b"changed".to_vec()));
+        assert_eq!(
+            sync_state.get("file.txt").unwrap().etag,
+            storage.etag("file.txt").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_update_handler_is_not_called_for_directories() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        file_system.put("folder/file.txt", b"hello");
+
+        e.add("update", &UpdatedEvent {});
+        e.call("update", &file_system.path("folder"));
+
+        assert!(storage.blob_names().is_empty());
     }
 
     #[test]
     fn test_create_handler_is_not_called_for_directories() {
-        let mock_file_system = MockFileSystem::new();
-        let mock_storage = MockStorage::new();
-        let mut e = EventHandler::new(&mock_storage, &mock_file_system);
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        file_system.put("folder/file.txt", b"hello");
+
+        e.add("create", &CreatedEvent {});
+        e.call("create", &file_system.path("folder"));
+
+        assert!(storage.blob_names().is_empty());
+    }
+
+    #[test]
+    fn test_remove_event_deletes_blob() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        storage.put("file.txt", b"hello");
+
+        e.add("remove", &RemovedEvent {});
+        e.call("remove", &file_system.path("file.txt"));
+
+        assert!(storage.blob_names().is_empty());
+    }
+
+    #[test]
+    fn test_remove_non_existing_blob_deletes_folder_blobs() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        storage.put("folder/file.txt", b"hello");
+
+        e.add("remove", &RemovedEvent {});
+        e.call("remove", &file_system.path("folder"));
+
+        assert!(storage.blob_names().is_empty());
+    }
+
+    #[test]
+    fn test_create_event_outside_root_folder_does_not_upload() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+
+        e.add("create", &CreatedEvent {});
+        e.call("create", &PathBuf::from("/elsewhere/file.txt"));
+
+        assert!(storage.blob_names().is_empty());
+    }
+
+    #[test]
+    fn test_remove_event_outside_root_folder_does_not_delete() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        storage.put("file.txt", b"hello");
+
+        e.add("remove", &RemovedEvent {});
+        e.call("remove", &PathBuf::from("/elsewhere/file.txt"));
+
+        assert_eq!(storage.blob_names(), vec!["file.txt"]);
+    }
+
+    #[test]
+    fn test_download_event_writes_downloaded_file() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        storage.put("file.txt", b"hello");
+        let path = file_system.path("file.txt");
+
+        e.add("download", &DownloadEvent {});
+        e.call("download", &path);
+
+        assert_eq!(file_system.contents(&path), Some(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn test_download_event_records_hash_of_downloaded_content() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        storage.put("file.txt", b"hello");
+
+        e.add("download", &DownloadEvent {});
+        e.call("download", &file_system.path("file.txt"));
+
+        assert_eq!(
+            sync_state.get("file.txt").unwrap().hash,
+            sync_state::hash(b"hello")
+        );
+    }
+
+    #[test]
+    fn test_download_event_keeps_unsynced_local_file_as_conflicted_copy() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        storage.put("file.txt", b"remote");
+        let path = file_system.put("file.txt", b"local");
+
+        e.add("download", &DownloadEvent {});
+        e.call("download", &path);
+
+        assert_eq!(file_system.contents(&path), Some(b"remote".to_vec()));
+        let conflicted: Vec<PathBuf> = file_system
+            .list_files()
+            .into_iter()
+            .filter(|p| *p != path)
+            .collect();
+        assert_eq!(conflicted.len(), 1);
+        assert_eq!(
+            file_system.contents(&conflicted[0]),
+            Some(b"local".to_vec())
+        );
+        assert_eq!(storage.blob_names().len(), 2);
+    }
+
+    #[test]
+    fn test_download_event_replaces_unsynced_local_file_with_same_content() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        let etag = storage.put("file.txt", b"same");
+        let path = file_system.put("file.txt", b"same");
+
+        e.add("download", &DownloadEvent {});
+        e.call("download", &path);
+
+        assert_eq!(file_system.list_files(), vec![path]);
+        assert_eq!(storage.blob_names(), vec!["file.txt"]);
+        assert_eq!(sync_state.get("file.txt").unwrap().etag, etag);
+    }
+
+    #[test]
+    fn test_remote_remove_event_removes_local_file() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        storage.put("file.txt", b"hello");
+        let path = file_system.put("file.txt", b"hello");
+
+        e.add("remote_remove", &RemoteRemovedEvent {});
+        e.call("remote_remove", &path);
+
+        assert_eq!(file_system.contents(&path), None);
+        assert_eq!(storage.blob_names(), vec!["file.txt"]);
+    }
+
+    #[test]
+    fn test_create_event_skips_upload_when_unchanged_since_sync() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        synced(&sync_state, "file.txt", b"hello", "etag");
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        let path = file_system.put("file.txt", b"hello");
+
+        e.add("create", &CreatedEvent {});
+        e.call("create", &path);
+
+        assert!(storage.blob_names().is_empty());
+    }
+
+    #[test]
+    fn test_create_event_records_sync_state() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        let path = file_system.put("file.txt", b"hello");
+
+        e.add("create", &CreatedEvent {});
+        e.call("create", &path);
+
+        assert_eq!(
+            sync_state.get("file.txt").unwrap().etag,
+            storage.etag("file.txt").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_create_event_keeps_conflicted_copy_when_blob_changed_remotely() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        synced(&sync_state, "file.txt", b"previous", "previous");
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        storage.put("file.txt", b"remote");
+        let path = file_system.put("file.txt", b"local");
+
+        e.add("create", &CreatedEvent {});
+        e.call("create", &path);
+
+        assert_eq!(file_system.contents(&path), Some(b"remote".to_vec()));
+        let conflicted: Vec<PathBuf> = file_system
+            .list_files()
+            .into_iter()
+            .filter(|p| *p != path)
+            .collect();
+        assert_eq!(conflicted.len(), 1);
+        assert_eq!(
+            file_system.contents(&conflicted[0]),
+            Some(b"local".to_vec())
+        );
+        assert_eq!(storage.blob_names().len(), 2);
+    }
+
+    #[test]
+    fn test_rename_event_moves_blob() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        storage.put("old.txt", b"stored");
+        // different local content shows the blob was copied, not uploaded again
+        file_system.put("new.txt", b"local");
+
+        e.add_rename("rename", &RenamedEvent {});
+        e.call_rename(
+            "rename",
+            &file_system.path("old.txt"),
+            &file_system.path("new.txt"),
+        );
+
+        assert_eq!(storage.blob_names(), vec!["new.txt"]);
+        assert_eq!(storage.contents("new.txt"), Some(b"stored".to_vec()));
+    }
+
+    #[test]
+    fn test_rename_event_moves_sync_state() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let etag = storage.put("old.txt", b"hello");
+        synced(&sync_state, "old.txt", b"hello", &etag);
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        file_system.put("new.txt", b"hello");
+
+        e.add_rename("rename", &RenamedEvent {});
+        e.call_rename(
+            "rename",
+            &file_system.path("old.txt"),
+            &file_system.path("new.txt"),
+        );
+
+        assert!(sync_state.get("old.txt").is_none());
+        assert_eq!(
+            sync_state.get("new.txt").unwrap().etag,
+            storage.etag("new.txt").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_rename_event_moves_every_blob_in_folder() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        storage.put("old/a.txt", b"a");
+        storage.put("old/sub/b.txt", b"b");
+        file_system.put("new/a.txt", b"a");
+        file_system.put("new/sub/b.txt", b"b");
+
+        e.add_rename("rename", &RenamedEvent {});
+        e.call_rename("rename", &file_system.path("old"), &file_system.path("new"));
+
+        assert_eq!(storage.blob_names(), vec!["new/a.txt", "new/sub/b.txt"]);
+    }
+
+    #[test]
+    fn test_rename_event_uploads_file_when_blob_cannot_be_copied() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let etag = storage.put("old.txt", b"hello");
+        synced(&sync_state, "old.txt", b"hello", &etag);
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        file_system.put("new.txt", b"hello");
+        storage.refuse("old.txt", 1);
+
+        e.add_rename("rename", &RenamedEvent {});
+        e.call_rename(
+            "rename",
+            &file_system.path("old.txt"),
+            &file_system.path("new.txt"),
+        );
+
+        assert_eq!(storage.blob_names(), vec!["new.txt"]);
+        assert!(sync_state.get("old.txt").is_none());
+        assert_eq!(
+            sync_state.get("new.txt").unwrap().etag,
+            storage.etag("new.txt").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_rename_event_keeps_old_blob_when_file_cannot_be_uploaded() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let etag = storage.put("old.txt", b"hello");
+        synced(&sync_state, "old.txt", b"hello", &etag);
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        file_system.put("new.txt", b"hello");
+        storage.refuse("old.txt", 1);
+        storage.refuse("new.txt", 1);
+
+        e.add_rename("rename", &RenamedEvent {});
+        e.call_rename(
+            "rename",
+            &file_system.path("old.txt"),
+            &file_system.path("new.txt"),
+        );
+
+        assert_eq!(storage.blob_names(), vec!["old.txt"]);
+        assert!(sync_state.get("old.txt").is_some());
+    }
+
+    // one blob per page so that continuation markers are followed
+    fn folder_storage() -> MemoryStorage {
+        let storage = MemoryStorage::with_page_size(1);
+        storage.put("folder/a.txt", b"a");
+        storage.put("folder/sub/b c.txt", b"b");
+        storage
+    }
+
+    #[test]
+    fn test_remove_folder_deletes_every_blob_in_folder() {
+        let file_system = MemoryFileSystem::new();
+        let storage = folder_storage();
+        let sync_state = sync_state::SyncState::new();
+        // listed names are encoded before their sync state is looked up
+        synced(&sync_state, "folder/sub/b%20c.txt", b"b", "etag");
+
+        let failed = delete_folder("folder", &storage, &file_system, &sync_state).unwrap();
+
+        assert!(failed.is_empty());
+        assert!(storage.blob_names().is_empty());
+        assert!(sync_state.get("folder/sub/b%20c.txt").is_none());
+    }
+
+    #[test]
+    fn test_remove_folder_retries_failed_deletes() {
+        let file_system = MemoryFileSystem::new();
+        let storage = folder_storage();
+        storage.fail("folder/a.txt", 1);
+        let sync_state = sync_state::SyncState::new();
+
+        let failed = delete_folder("folder", &storage, &file_system, &sync_state).unwrap();
+
+        assert!(failed.is_empty());
+        assert!(storage.blob_names().is_empty());
+    }
+
+    #[test]
+    fn test_remove_folder_reports_blobs_that_keep_failing() {
+        let file_system = MemoryFileSystem::new();
+        let storage = folder_storage();
+        storage.fail("folder/a.txt", DELETE_ATTEMPTS);
+        let sync_state = sync_state::SyncState::new();
+
+        let failed = delete_folder("folder", &storage, &file_system, &sync_state).unwrap();
+
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, "folder/a.txt");
+        assert_eq!(storage.blob_names(), vec!["folder/a.txt"]);
+    }
+
+    #[test]
+    fn test_remove_folder_returns_listing_failure() {
+        let file_system = MemoryFileSystem::new();
+        let storage = folder_storage();
+        storage.fail("folder/", 1);
+        let sync_state = sync_state::SyncState::new();
+
+        let result = delete_folder("folder", &storage, &file_system, &sync_state);
+
+        assert!(result.is_err());
+        assert_eq!(storage.blob_names().len(), 2);
+    }
+
+    #[test]
+    fn test_remove_folder_is_retried_when_listing_fails() {
+        let file_system = MemoryFileSystem::new();
+        let storage = folder_storage();
+        storage.fail("folder/", 1);
+        let sync_state = sync_state::SyncState::new();
+
+        let result = delete_path("folder", &storage, &file_system, &sync_state);
+
+        match result {
+            Err(ref e) if e.is_retryable() => (),
+            _ => panic!("expected a retryable error"),
+        }
+    }
+
+    #[test]
+    fn test_remove_folder_forgets_sync_state_of_deleted_blobs() {
+        let file_system = MemoryFileSystem::new();
+        let storage = folder_storage();
+        let sync_state = sync_state::SyncState::new();
+        synced(&sync_state, "folder/a.txt", b"a", "etag");
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+
+        e.add("remove", &RemovedEvent {});
+        e.call("remove", &file_system.path("folder"));
+
+        assert!(sync_state.get("folder/a.txt").is_none());
+    }
+
+    #[test]
+    fn test_create_event_is_queued_while_storage_is_unavailable() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        storage.set_offline(true);
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        let path = file_system.put("file.txt", b"hello");
 
         e.add("create", &CreatedEvent {});
-        e.call("create", &PathBuf::from("/"));
+        e.call("create", &path);
+
+        assert_eq!(
+            sync_state.next_operation(),
+            Some(Operation::Upload(String::from("file.txt")))
+        );
+    }
+
+    #[test]
+    fn test_events_queue_behind_pending_operations_on_the_same_blob() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        storage.put("a.txt", b"a");
+        sync_state.enqueue(Operation::Upload(String::from("a.txt")));
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+
+        e.add("remove", &RemovedEvent {});
+        e.call("remove", &file_system.path("a.txt"));
 
-        assert_eq!(*mock_storage.upload_called.borrow(), false);
+        assert_eq!(storage.blob_names(), vec!["a.txt"]);
+        assert_eq!(
+            sync_state.pending_operations(),
+            vec![Operation::Delete(String::from("a.txt"))]
+        );
     }
 
     #[test]
-    fn test_remove_event_calls_storage_delete() {
-        let mock_file_system = MockFileSystem::new();
-        let mock_storage = MockStorage::new();
-        let mut e = EventHandler::new(&mock_storage, &mock_file_system);
+    fn test_events_on_other_blobs_run_while_operations_are_pending() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        storage.put("b.txt", b"b");
+        sync_state.enqueue(Operation::Upload(String::from("a.txt")));
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
 
         e.add("remove", &RemovedEvent {});
-        e.call("remove", &PathBuf::new());
+        e.call("remove", &file_system.path("b.txt"));
 
-        assert!(*mock_storage.delete_called.borrow());
+        assert!(storage.blob_names().is_empty());
+        assert_eq!(
+            sync_state.pending_operations(),
+            vec![Operation::Upload(String::from("a.txt"))]
+        );
     }
 
     #[test]
-    fn test_remove_non_existing_file_calls_list_folder_blobs() {
-        let mock_file_system = MockFileSystem::new();
-        let mock_storage = MockStorage::new();
-        mock_storage.set_return_path_not_found_error(true);
-        let mut e = EventHandler::new(&mock_storage, &mock_file_system);
+    fn test_events_inside_a_pending_folder_operation_are_queued() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        storage.put("new/a.txt", b"a");
+        sync_state.enqueue(Operation::Rename(String::from("old"), String::from("new")));
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
 
         e.add("remove", &RemovedEvent {});
-        e.call("remove", &PathBuf::new());
+        e.call("remove", &file_system.path("new/a.txt"));
+
+        assert_eq!(storage.blob_names(), vec!["new/a.txt"]);
+    }
+
+    #[test]
+    fn test_replay_runs_queued_operations_in_order() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        storage.put("a.txt", b"a");
+        // deleting b.txt before the rename would leave it behind
+        sync_state.enqueue(Operation::Rename(
+            String::from("a.txt"),
+            String::from("b.txt"),
+        ));
+        sync_state.enqueue(Operation::Delete(String::from("b.txt")));
+        let e = EventHandler::new(&storage, &file_system, &sync_state);
+
+        e.replay_queued();
+
+        assert!(storage.blob_names().is_empty());
+        assert!(!sync_state.has_pending_operations());
+    }
+
+    #[test]
+    fn test_replay_keeps_operations_while_storage_is_unavailable() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        storage.put("a.txt", b"a");
+        storage.set_offline(true);
+        let sync_state = sync_state::SyncState::new();
+        sync_state.enqueue(Operation::Delete(String::from("a.txt")));
+        let e = EventHandler::new(&storage, &file_system, &sync_state);
+
+        e.replay_queued();
+
+        assert_eq!(
+            sync_state.next_operation(),
+            Some(Operation::Delete(String::from("a.txt")))
+        );
+    }
+
+    #[test]
+    fn test_replay_skips_past_an_operation_that_keeps_failing() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        storage.put("a.txt", b"a");
+        storage.put("b.txt", b"b");
+        storage.fail("a.txt", 1);
+        let sync_state = sync_state::SyncState::new();
+        sync_state.enqueue(Operation::Delete(String::from("a.txt")));
+        sync_state.enqueue(Operation::Rename(
+            String::from("a.txt"),
+            String::from("c.txt"),
+        ));
+        sync_state.enqueue(Operation::Delete(String::from("b.txt")));
+        let e = EventHandler::new(&storage, &file_system, &sync_state);
+
+        e.replay_queued();
+
+        assert_eq!(storage.blob_names(), vec!["a.txt"]);
+        assert_eq!(
+            sync_state.pending_operations(),
+            vec![
+                Operation::Delete(String::from("a.txt")),
+                Operation::Rename(String::from("a.txt"), String::from("c.txt")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_created_file_content_is_stored() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        let path = file_system.put("folder/my file.txt", b"hello");
+
+        e.add("create", &CreatedEvent {});
+        e.call("create", &path);
+
+        assert_eq!(
+            storage.contents("folder/my file.txt"),
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(
+            sync_state.get("folder/my%20file.txt").unwrap().etag,
+            storage.etag("folder/my file.txt").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_conflicting_edits_keep_both_versions() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        e.add("create", &CreatedEvent {});
+        e.add("update", &UpdatedEvent {});
+        let path = file_system.put("file.txt", b"original");
+        e.call("create", &path);
+
+        storage.put("file.txt", b"remote edit");
+        file_system.put("file.txt", b"local edit");
+        e.call("update", &path);
+
+        assert_eq!(storage.contents("file.txt"), Some(b"remote edit".to_vec()));
+        let conflicted: Vec<String> = storage
+            .blob_names()
+            .into_iter()
+            .filter(|n| n != "file.txt")
+            .collect();
+        assert_eq!(conflicted.len(), 1);
+        assert_eq!(
+            storage.contents(&conflicted[0]),
+            Some(b"local edit".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_new_file_does_not_replace_blob_created_remotely() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        storage.put("file.txt", b"remote");
+        let path = file_system.put("file.txt", b"local");
+
+        e.add("create", &CreatedEvent {});
+        e.call("create", &path);
+
+        assert_eq!(storage.contents("file.txt"), Some(b"remote".to_vec()));
+        assert_eq!(file_system.contents(&path), Some(b"remote".to_vec()));
+        let conflicted: Vec<String> = storage
+            .blob_names()
+            .into_iter()
+            .filter(|n| n != "file.txt")
+            .collect();
+        assert_eq!(conflicted.len(), 1);
+        assert_eq!(storage.contents(&conflicted[0]), Some(b"local".to_vec()));
+    }
+
+    #[test]
+    fn test_repeated_conflicts_keep_every_conflicted_copy() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        e.add("create", &CreatedEvent {});
+        e.add("update", &UpdatedEvent {});
+        let path = file_system.put("file.txt", b"original");
+        e.call("create", &path);
+
+        for edit in &["first", "second"] {
+            storage.put("file.txt", b"remote edit");
+            file_system.put("file.txt", edit.as_bytes());
+            e.call("update", &path);
+        }
+
+        let mut conflicted: Vec<Vec<u8>> = storage
+            .blob_names()
+            .into_iter()
+            .filter(|n| n != "file.txt")
+            .map(|n| storage.contents(&n).unwrap())
+            .collect();
+        conflicted.sort();
+        assert_eq!(conflicted, vec![b"first".to_vec(), b"second".to_vec()]);
+    }
+
+    #[test]
+    fn test_renamed_folder_is_moved_in_storage() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::with_page_size(1);
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        storage.put("old/a.txt", b"a");
+        storage.put("old/b.txt", b"b");
+        // the folder has already been renamed locally
+        file_system.put("new/a.txt", b"a");
+        file_system.put("new/b.txt", b"b");
+
+        e.add_rename("rename", &RenamedEvent {});
+        e.call_rename("rename", &file_system.path("old"), &file_system.path("new"));
+
+        assert_eq!(storage.blob_names(), vec!["new/a.txt", "new/b.txt"]);
+        assert_eq!(storage.contents("new/b.txt"), Some(b"b".to_vec()));
+    }
+
+    #[test]
+    fn test_downloaded_blob_is_written_locally() {
+        let file_system = MemoryFileSystem::new();
+        let storage = MemoryStorage::new();
+        let sync_state = sync_state::SyncState::new();
+        let mut e = EventHandler::new(&storage, &file_system, &sync_state);
+        let etag = storage.put("folder/file.txt", b"hello");
+        let path = file_system.path("folder/file.txt");
+
+        e.add("download", &DownloadEvent {});
+        e.call("download", &path);
 
-        assert!(*mock_storage.list_folder_blobs_called.borrow());
+        assert_eq!(file_system.contents(&path), Some(b"hello".to_vec()));
+        assert_eq!(sync_state.get("folder/file.txt").unwrap().etag, etag);
     }
 }
@@ -1,13 +1,61 @@
 use super::bucket;
-use std::fs::File;
-use std::io::Read;
-use std::path::{Path, PathBuf};
-use url::percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET};
+use super::sync_state;
+use chrono::{DateTime, Utc};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek};
+use std::path::{Component, Path, PathBuf};
+use url::percent_encoding::{percent_decode, utf8_percent_encode, DEFAULT_ENCODE_SET};
+
+#[derive(Debug, Fail)]
+pub enum FileSystemError {
+    #[fail(display = "{:?} is not inside the root folder", _0)]
+    OutsideRootFolder(PathBuf),
+    #[fail(display = "{:?} is not a valid UTF-8 file name", _0)]
+    InvalidFileName(PathBuf),
+    #[fail(display = "The blob name {:?} would reach outside the root folder", _0)]
+    UnsafeBlobName(String),
+    #[fail(display = "An io error has occurred - {:?}", _0)]
+    IOError(io::Error),
+}
+
+impl From<io::Error> for FileSystemError {
+    fn from(error: io::Error) -> Self {
+        FileSystemError::IOError(error)
+    }
+}
+
+// Uploads need to rewind their content when an attempt has to be retried.
+pub trait FileContent: Read + Seek {}
+
+impl<T: Read + Seek> FileContent for T {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileInfo {
+    pub size: u64,
+    pub last_modified: DateTime<Utc>,
+}
 
 pub trait FileSystem {
-    fn get_blob_name(&self, p: &PathBuf) -> String;
-    fn get_file_contents(&self, p: &PathBuf) -> Vec<u8>;
+    fn list_files(&self) -> Vec<PathBuf>;
+    fn get_file_info(&self, p: &PathBuf) -> Result<FileInfo, FileSystemError>;
+    fn get_blob_name(&self, p: &PathBuf) -> Result<String, FileSystemError>;
+    fn get_file_path(&self, blob_name: &str) -> Result<PathBuf, FileSystemError>;
+    fn open_file(&self, p: &PathBuf) -> Result<Box<FileContent>, FileSystemError>;
+    fn write_file(&self, p: &PathBuf, content: &mut Read) -> Result<(), FileSystemError>;
+    fn remove_file(&self, p: &PathBuf) -> Result<(), FileSystemError>;
     fn encode_file_name(&self, f: &str) -> String;
+
+    fn get_file_hash(&self, p: &PathBuf) -> Result<String, FileSystemError> {
+        Ok(sync_state::hash_reader(self.open_file(p)?)?)
+    }
+
+    fn is_dir(&self, p: &PathBuf) -> bool {
+        p.is_dir()
+    }
+
+    fn is_file(&self, p: &PathBuf) -> bool {
+        p.is_file()
+    }
 }
 
 pub struct LocalFileSystem {
@@ -15,10 +63,54 @@ pub struct LocalFileSystem {
 }
 
 impl FileSystem for LocalFileSystem {
-    fn get_blob_name(&self, p: &PathBuf) -> String {
+    fn list_files(&self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        let mut folders = vec![PathBuf::from(&self.root_folder)];
+
+        while let Some(folder) = folders.pop() {
+            let entries = match fs::read_dir(&folder) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    trace!("Error reading folder {:?} - {}", folder, e);
+                    continue;
+                }
+            };
+            for entry in entries.filter_map(|e| e.ok()) {
+                let path = entry.path();
+                if sync_state::is_state_path(&self.root_folder, &path) {
+                    continue;
+                } else if path.is_dir() {
+                    folders.push(path);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+
+        files
+    }
+
+    fn get_file_info(&self, p: &PathBuf) -> Result<FileInfo, FileSystemError> {
+        let metadata = fs::metadata(p)?;
+        Ok(FileInfo {
+            size: metadata.len(),
+            last_modified: DateTime::<Utc>::from(metadata.modified()?),
+        })
+    }
+
+    fn get_blob_name(&self, p: &PathBuf) -> Result<String, FileSystemError> {
         let root = Path::new(&self.root_folder);
-        let stripped = p.strip_prefix(root).unwrap();
-        self.encode_file_name(stripped.to_str().unwrap())
+        let stripped = p
+            .strip_prefix(root)
+            .map_err(|_| FileSystemError::OutsideRootFolder(p.clone()))?;
+        match stripped.to_str() {
+            Some(name) => Ok(self.encode_file_name(name)),
+            None => Err(FileSystemError::InvalidFileName(p.clone())),
+        }
+    }
+
+    fn get_file_path(&self, blob_name: &str) -> Result<PathBuf, FileSystemError> {
+        blob_path(Path::new(&self.root_folder), blob_name)
     }
 
     fn encode_file_name(&self, f: &str) -> String {
@@ -27,12 +119,65 @@ impl FileSystem for LocalFileSystem {
         utf8_percent_encode(&normalized, DEFAULT_ENCODE_SET).collect()
     }
 
-    fn get_file_contents(&self, p: &PathBuf) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        let mut file = File::open(p).unwrap();
-        file.read_to_end(&mut buffer).unwrap();
-        buffer
+    fn open_file(&self, p: &PathBuf) -> Result<Box<FileContent>, FileSystemError> {
+        Ok(Box::new(BufReader::new(File::open(p)?)))
+    }
+
+    fn write_file(&self, p: &PathBuf, content: &mut Read) -> Result<(), FileSystemError> {
+        if let Some(parent) = p.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // content is written to the state folder first and moved into place once
+        // complete, so an interrupted download never leaves a partial file behind
+        let staging_folder = Path::new(&self.root_folder)
+            .join(sync_state::STATE_FOLDER)
+            .join("downloads");
+        fs::create_dir_all(&staging_folder)?;
+        let staging_path = staging_folder.join(format!(
+            "{:x}",
+            md5::compute(p.to_string_lossy().as_bytes())
+        ));
+
+        let result = File::create(&staging_path)
+            .and_then(|mut file| io::copy(content, &mut file).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&staging_path, p));
+        if result.is_err() {
+            let _ = fs::remove_file(&staging_path);
+        }
+        Ok(result?)
+    }
+
+    fn remove_file(&self, p: &PathBuf) -> Result<(), FileSystemError> {
+        Ok(fs::remove_file(p)?)
+    }
+}
+
+// Blob names come from whoever can write to the container, so a name is only
+// turned into a path when every part of it stays inside the root folder and
+// out of the state folder.
+pub fn blob_path(root_folder: &Path, blob_name: &str) -> Result<PathBuf, FileSystemError> {
+    let decoded = percent_decode(blob_name.as_bytes()).decode_utf8_lossy();
+    let parts: Vec<&str> = decoded.split('/').filter(|p| !p.is_empty()).collect();
+    let unsafe_part = |part: &&str| {
+        let mut components = Path::new(part).components();
+        let single_name = match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => true,
+            _ => false,
+        };
+        let drive = part.len() >= 2 && part.as_bytes()[1] == b':';
+        !single_name || drive || part.contains('\\')
+    };
+
+    if parts.is_empty() || parts[0] == sync_state::STATE_FOLDER || parts.iter().any(unsafe_part) {
+        return Err(FileSystemError::UnsafeBlobName(String::from(blob_name)));
+    }
+
+    let mut path = root_folder.to_path_buf();
+    for part in parts {
+        path.push(part);
     }
+    Ok(path)
 }
 
 impl LocalFileSystem {
@@ -46,36 +191,170 @@ impl LocalFileSystem {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use azure_client::AzureAuth;
+    use reconcile::ReconcilePolicy;
+    use retry::RetryPolicy;
+    use std::env;
     use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn test_config(root_folder: &str) -> bucket::Config {
+        bucket::Config {
+            root_folder: String::from(root_folder),
+            storage_backend: bucket::StorageBackend::Azure,
+            storage_account: String::from(""),
+            azure_auth: AzureAuth::SharedKey(String::from("")),
+            root_container_name: String::from(""),
+            blob_endpoint: None,
+            poll_interval: Duration::from_secs(60),
+            reconcile_policy: ReconcilePolicy::Download,
+            block_size: 4 * 1024 * 1024,
+            retry_policy: RetryPolicy::default(),
+            workers: 4,
+        }
+    }
 
     #[cfg(target_os = "windows")]
     #[test]
     fn test_path_conversion_windows_format() {
-        let config = bucket::Config {
-            root_folder: String::from("C:/bucket"),
-            storage_account: String::from(""),
-            account_key: String::from(""),
-            root_container_name: String::from(""),
-        };
+        let config = test_config("C:/bucket");
 
         let fs = LocalFileSystem::new(&config);
         let path = PathBuf::from("C:/bucket\\folder1\\folder2\\file.txt");
-        let blob_name = fs.get_blob_name(&path);
+        let blob_name = fs.get_blob_name(&path).unwrap();
         assert_eq!("folder1/folder2/file.txt", blob_name);
     }
 
     #[test]
     fn test_path_conversion_unix_format() {
-        let config = bucket::Config {
-            root_folder: String::from("/bucket"),
-            storage_account: String::from(""),
-            account_key: String::from(""),
-            root_container_name: String::from(""),
-        };
+        let config = test_config("/bucket");
 
         let fs = LocalFileSystem::new(&config);
         let path = PathBuf::from("/bucket/folder1/folder2/file.txt");
-        let blob_name = fs.get_blob_name(&path);
+        let blob_name = fs.get_blob_name(&path).unwrap();
         assert_eq!("folder1/folder2/file.txt", blob_name);
     }
+
+    #[test]
+    fn test_blob_name_conversion_to_path() {
+        let config = test_config("/bucket");
+
+        let fs = LocalFileSystem::new(&config);
+        let path = fs.get_file_path("folder1/folder%202/file.txt").unwrap();
+        assert_eq!(PathBuf::from("/bucket/folder1/folder 2/file.txt"), path);
+    }
+
+    #[test]
+    fn test_blob_names_outside_root_folder_are_rejected() {
+        let root = Path::new("/bucket");
+        for blob_name in &[
+            "../../.bashrc",
+            "folder/%2E%2E/%2E%2E/.bashrc",
+            "./file.txt",
+            "folder/..",
+            "..%5C..%5C.bashrc",
+            "C:/Windows/file.txt",
+            "folder/c:file.txt",
+            ".bucket/sync.json",
+            "/",
+        ] {
+            match blob_path(root, blob_name) {
+                Err(FileSystemError::UnsafeBlobName(_)) => (),
+                r => panic!("expected {} to be rejected, got {:?}", blob_name, r),
+            }
+        }
+        assert_eq!(
+            blob_path(root, "/folder//..file.txt").unwrap(),
+            PathBuf::from("/bucket/folder/..file.txt")
+        );
+    }
+
+    #[test]
+    fn test_blob_name_round_trip() {
+        let config = test_config("/bucket");
+
+        let fs = LocalFileSystem::new(&config);
+        let path = PathBuf::from("/bucket/folder 1/file #1.txt");
+        let blob_name = fs.get_blob_name(&path).unwrap();
+        assert_eq!(path, fs.get_file_path(&blob_name).unwrap());
+    }
+
+    #[test]
+    fn test_path_outside_root_folder_is_an_error() {
+        let config = test_config("/bucket");
+
+        let fs = LocalFileSystem::new(&config);
+        match fs.get_blob_name(&PathBuf::from("/elsewhere/file.txt")) {
+            Err(FileSystemError::OutsideRootFolder(_)) => (),
+            _ => panic!("expected OutsideRootFolder"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_file_name_is_an_error() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let config = test_config("/bucket");
+
+        let fs = LocalFileSystem::new(&config);
+        let path = PathBuf::from("/bucket").join(OsStr::from_bytes(b"file\xff.txt"));
+        match fs.get_blob_name(&path) {
+            Err(FileSystemError::InvalidFileName(_)) => (),
+            _ => panic!("expected InvalidFileName"),
+        }
+    }
+
+    #[test]
+    fn test_opening_missing_file_is_an_error() {
+        let config = test_config("/bucket");
+
+        let fs = LocalFileSystem::new(&config);
+        assert!(fs.open_file(&PathBuf::from("/bucket/missing.txt")).is_err());
+    }
+
+    #[test]
+    fn test_written_file_can_be_read_back() {
+        let root_folder = env::temp_dir().join(format!("bucket-fs-{}", ::std::process::id()));
+        let config = test_config(root_folder.to_str().unwrap());
+
+        let fs = LocalFileSystem::new(&config);
+        let path = root_folder.join("folder").join("file.txt");
+        let data = vec![1u8; 100_000];
+        fs.write_file(&path, &mut &data[..]).unwrap();
+
+        let mut written = Vec::new();
+        fs.open_file(&path)
+            .unwrap()
+            .read_to_end(&mut written)
+            .unwrap();
+        assert_eq!(data, written);
+        assert_eq!(fs.get_file_hash(&path).unwrap(), sync_state::hash(&data));
+        assert_eq!(fs.list_files(), vec![path]);
+        fs::remove_dir_all(&root_folder).unwrap();
+    }
+
+    #[test]
+    fn test_only_the_root_state_folder_is_left_out_of_listings() {
+        let root_folder = env::temp_dir().join(format!("bucket-fs-state-{}", ::std::process::id()));
+        let config = test_config(root_folder.to_str().unwrap());
+
+        let fs = LocalFileSystem::new(&config);
+        let state = root_folder.join(sync_state::STATE_FOLDER).join("state.log");
+        let nested = root_folder
+            .join("sub")
+            .join(sync_state::STATE_FOLDER)
+            .join("file.txt");
+        fs.write_file(&state, &mut &b"state"[..]).unwrap();
+        fs.write_file(&nested, &mut &b"hello"[..]).unwrap();
+
+        assert_eq!(fs.list_files(), vec![nested.clone()]);
+        assert_eq!(
+            fs.get_file_path(&fs.get_blob_name(&nested).unwrap())
+                .unwrap(),
+            nested
+        );
+        fs::remove_dir_all(&root_folder).unwrap();
+    }
 }
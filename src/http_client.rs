@@ -0,0 +1,284 @@
+use super::storage::StorageError;
+use chrono::{DateTime, Utc};
+use futures::{Future, Stream};
+use hyper::client::HttpConnector;
+use hyper::{Body, HeaderMap, Method, Request, StatusCode};
+use hyper_tls::HttpsConnector;
+use std::cell::RefCell;
+use std::io;
+use std::time::Duration;
+use tokio_core::reactor::Core;
+use xml::{Element, Xml};
+
+pub type Response = (StatusCode, HeaderMap, Vec<u8>);
+
+pub type ResponseFuture = Box<Future<Item = Response, Error = StorageError>>;
+
+// One reactor and connection pool for every request a storage backend makes,
+// so connections are kept alive and reused rather than paying for a TLS
+// handshake each time.
+pub struct HttpClient {
+    core: RefCell<Core>,
+    http: hyper::Client<HttpsConnector<HttpConnector>>,
+}
+
+impl HttpClient {
+    pub fn new() -> Result<HttpClient, StorageError> {
+        let https = HttpsConnector::new(4)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+        Ok(HttpClient {
+            core: RefCell::new(Core::new()?),
+            http: hyper::Client::builder().build(https),
+        })
+    }
+
+    pub fn request(
+        &self,
+        method: Method,
+        uri: &str,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    ) -> ResponseFuture {
+        let mut request = Request::builder();
+        request.method(method).uri(uri);
+        for (name, value) in &headers {
+            request.header(name.as_str(), value.as_str());
+        }
+        let request = match request.body(Body::from(body)) {
+            Ok(request) => request,
+            Err(e) => {
+                return Box::new(::futures::future::err(StorageError::from(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    e.to_string(),
+                ))))
+            }
+        };
+
+        Box::new(
+            self.http
+                .request(request)
+                .and_then(|response| {
+                    let status = response.status();
+                    let headers = response.headers().clone();
+                    response
+                        .into_body()
+                        .concat2()
+                        .map(move |body| (status, headers, body.to_vec()))
+                })
+                .map_err(StorageError::ConnectionError),
+        )
+    }
+
+    pub fn run<T>(
+        &self,
+        future: Box<Future<Item = T, Error = StorageError>>,
+    ) -> Result<T, StorageError> {
+        self.core.borrow_mut().run(future)
+    }
+}
+
+// Both services report missing blobs and failed conditions the same way, and
+// anything else unexpected is left for the retry policy to judge by status.
+pub fn check_status(
+    description: &str,
+    response: Response,
+    expected: StatusCode,
+) -> Result<(HeaderMap, Vec<u8>), StorageError> {
+    let (status, headers, body) = response;
+    match status {
+        s if s == expected || s == StatusCode::PARTIAL_CONTENT => Ok((headers, body)),
+        StatusCode::NOT_FOUND => Err(StorageError::PathNotFound),
+        StatusCode::PRECONDITION_FAILED => Err(StorageError::ConditionNotMet),
+        s => {
+            trace!(
+                "{} returned {} - {}",
+                description,
+                s,
+                String::from_utf8_lossy(&body)
+            );
+            Err(StorageError::UnexpectedStatus(s, retry_after(&headers)))
+        }
+    }
+}
+
+// Azure gives the delay in milliseconds when throttling, while Retry-After
+// can be either a number of seconds or a date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let millis = header_value(headers, "x-ms-retry-after-ms");
+    if let Ok(millis) = millis.parse() {
+        return Some(Duration::from_millis(millis));
+    }
+
+    let retry_after = header_value(headers, "retry-after");
+    if let Ok(seconds) = retry_after.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    DateTime::parse_from_rfc2822(&retry_after).ok().map(|date| {
+        date.with_timezone(&Utc)
+            .signed_duration_since(Utc::now())
+            .to_std()
+            .unwrap_or_default()
+    })
+}
+
+pub fn header_value(headers: &HeaderMap, name: &str) -> String {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim_matches('"').to_owned())
+        .unwrap_or_default()
+}
+
+// a blob of unknown length can't be read safely, as it would look empty
+pub fn content_length(headers: &HeaderMap) -> Result<u64, StorageError> {
+    header_value(headers, "content-length")
+        .parse()
+        .map_err(|_| {
+            StorageError::from(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The response has no valid content-length",
+            ))
+        })
+}
+
+pub fn parse_xml(body: &[u8]) -> Option<Element> {
+    String::from_utf8_lossy(body).parse().ok()
+}
+
+// responses aren't always namespaced the same way by every implementation of
+// a service, so elements are matched on name alone
+pub fn children<'a>(element: &'a Element, name: &'a str) -> impl Iterator<Item = &'a Element> + 'a {
+    element
+        .children
+        .iter()
+        .filter_map(move |child| match child {
+            Xml::ElementNode(e) if e.name == name => Some(e),
+            _ => None,
+        })
+}
+
+pub fn child_text(element: &Element, name: &str) -> Option<String> {
+    children(element, name).next().map(|e| e.content_str())
+}
+
+// percent encoding that leaves only unreserved characters as they are, which
+// is what both services expect when signing requests
+pub fn uri_encode(value: &str, encode_slash: bool) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b'/' if !encode_slash => String::from("/"),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: StatusCode) -> Response {
+        (status, HeaderMap::new(), Vec::new())
+    }
+
+    #[test]
+    fn test_expected_and_partial_statuses_succeed() {
+        assert!(check_status("GET", response(StatusCode::OK), StatusCode::OK).is_ok());
+        assert!(check_status("GET", response(StatusCode::PARTIAL_CONTENT), StatusCode::OK).is_ok());
+    }
+
+    #[test]
+    fn test_statuses_map_to_storage_errors() {
+        match check_status("GET", response(StatusCode::NOT_FOUND), StatusCode::OK) {
+            Err(StorageError::PathNotFound) => (),
+            _ => panic!("expected PathNotFound"),
+        }
+        match check_status(
+            "PUT",
+            response(StatusCode::PRECONDITION_FAILED),
+            StatusCode::CREATED,
+        ) {
+            Err(StorageError::ConditionNotMet) => (),
+            _ => panic!("expected ConditionNotMet"),
+        }
+        match check_status(
+            "PUT",
+            response(StatusCode::SERVICE_UNAVAILABLE),
+            StatusCode::CREATED,
+        ) {
+            Err(ref e) if e.is_retryable() => (),
+            _ => panic!("expected a retryable error"),
+        }
+        match check_status("PUT", response(StatusCode::FORBIDDEN), StatusCode::CREATED) {
+            Err(ref e) if !e.is_retryable() => (),
+            _ => panic!("expected a permanent error"),
+        }
+    }
+
+    fn throttled(name: &'static str, value: &str) -> Response {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        (StatusCode::SERVICE_UNAVAILABLE, headers, Vec::new())
+    }
+
+    fn requested_delay(response: Response) -> Option<Duration> {
+        match check_status("GET", response, StatusCode::OK) {
+            Err(e) => e.retry_after(),
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn test_retry_after_is_kept_with_the_error() {
+        assert_eq!(
+            requested_delay(throttled("x-ms-retry-after-ms", "1500")),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            requested_delay(throttled("retry-after", "30")),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            requested_delay(throttled("retry-after", "Wed, 21 Oct 2015 07:28:00 GMT")),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(
+            requested_delay(response(StatusCode::SERVICE_UNAVAILABLE)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_missing_content_length_is_an_error() {
+        let mut headers = HeaderMap::new();
+        assert!(content_length(&headers).is_err());
+
+        headers.insert("content-length", "nan".parse().unwrap());
+        assert!(content_length(&headers).is_err());
+
+        headers.insert("content-length", "42".parse().unwrap());
+        assert_eq!(content_length(&headers).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_children_ignore_namespaces() {
+        let element = parse_xml(br#"<a xmlns="urn:x"><b>1</b><c>2</c><b>3</b></a>"#).unwrap();
+
+        assert_eq!(children(&element, "b").count(), 2);
+        assert_eq!(child_text(&element, "c"), Some(String::from("2")));
+        assert_eq!(child_text(&element, "d"), None);
+    }
+
+    #[test]
+    fn test_uri_encode_keeps_only_unreserved_characters() {
+        assert_eq!(
+            uri_encode("folder/my file+1.txt", false),
+            "folder/my%20file%2B1.txt"
+        );
+        assert_eq!(uri_encode("a/b~c", true), "a%2Fb~c");
+    }
+}
@@ -0,0 +1,43 @@
+#![allow(dead_code)]
+#![allow(unused_variables)]
+
+extern crate base64;
+extern crate chrono;
+extern crate futures;
+extern crate hyper;
+extern crate hyper_tls;
+extern crate notify;
+extern crate ring;
+extern crate sentry;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+#[macro_use]
+extern crate log;
+extern crate md5;
+extern crate tokio_core;
+extern crate toml;
+extern crate url;
+extern crate xml;
+#[macro_use]
+extern crate failure;
+
+pub mod azure_client;
+pub mod bucket;
+pub mod config;
+mod conflict;
+pub mod event_handlers;
+pub mod file_system;
+mod http_client;
+pub mod local_storage;
+pub mod reconcile;
+mod remote_watcher;
+pub mod retry;
+pub mod s3_storage;
+pub mod storage;
+pub mod sync_state;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+mod upload_journal;
+mod worker_pool;
@@ -0,0 +1,611 @@
+use super::bucket;
+use super::file_system::FileContent;
+use super::storage::{BlobData, BlobInfo, BlobPage, Precondition, Storage, StorageError};
+use super::sync_state::{self, HashingReader};
+use chrono::{DateTime, Utc};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::percent_encoding::percent_decode;
+
+static NEXT_UPLOAD: AtomicUsize = AtomicUsize::new(0);
+
+// a lock this old was left behind by a writer that stopped partway
+const LOCK_STALE_AFTER: Duration = Duration::from_secs(30);
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+// Stores blobs as files under another folder, such as a mounted network share.
+// Blob names map onto paths the same way they map onto Azure blobs, and
+// bookkeeping is kept in a state folder alongside them.
+pub struct LocalStorage {
+    folder: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(config: &bucket::Config, folder: &str) -> Result<LocalStorage, StorageError> {
+        // the config has already checked that it doesn't overlap ROOT_FOLDER
+        let folder = PathBuf::from(folder);
+        fs::create_dir_all(&folder)?;
+        Ok(LocalStorage {
+            folder: folder.canonicalize()?,
+        })
+    }
+
+    fn blob_path(&self, blob_name: &str) -> PathBuf {
+        let mut path = self.folder.clone();
+        // nothing outside the folder can be reached through a blob name
+        for part in blob_name
+            .split('/')
+            .filter(|p| !p.is_empty() && *p != "." && *p != "..")
+        {
+            path.push(part);
+        }
+        path
+    }
+
+    fn state_path(&self, name: &str) -> PathBuf {
+        self.folder.join(sync_state::STATE_FOLDER).join(name)
+    }
+
+    // The md5 of each blob is recorded alongside it and used as its etag, since
+    // timestamps can be too coarse to tell two quick writes apart. A record is
+    // only trusted while the file has the size and modified time it had then.
+    fn md5_path(&self, blob_name: &str) -> PathBuf {
+        self.state_path("md5")
+            .join(format!("{:x}", md5::compute(blob_name.as_bytes())))
+    }
+
+    fn read_md5(&self, blob_name: &str, metadata: &fs::Metadata) -> Option<String> {
+        let record = fs::read_to_string(self.md5_path(blob_name)).ok()?;
+        let mut fields = record.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some(stamp), Some(md5)) if stamp == file_stamp(metadata) => Some(md5.to_owned()),
+            _ => None,
+        }
+    }
+
+    fn write_md5(
+        &self,
+        blob_name: &str,
+        metadata: &fs::Metadata,
+        md5: &str,
+    ) -> Result<(), io::Error> {
+        let path = self.md5_path(blob_name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, format!("{} {}", file_stamp(metadata), md5))
+    }
+
+    // files changed by anything other than bucket are hashed again
+    fn blob_md5(&self, blob_name: &str, path: &Path) -> Result<String, io::Error> {
+        let metadata = fs::metadata(path)?;
+        if let Some(md5) = self.read_md5(blob_name, &metadata) {
+            return Ok(md5);
+        }
+        let md5 = sync_state::hash_reader(File::open(path)?)?;
+        if let Err(e) = self.write_md5(blob_name, &metadata, &md5) {
+            trace!("Error recording the md5 of {} - {}", blob_name, e);
+        }
+        Ok(md5)
+    }
+
+    fn current_etag(&self, blob_name: &str, path: &Path) -> Result<Option<String>, io::Error> {
+        match self.blob_md5(blob_name, path) {
+            Ok(md5) => Ok(Some(md5_etag(&md5))),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn staging_path(&self) -> Result<PathBuf, io::Error> {
+        let folder = self.state_path("uploads");
+        fs::create_dir_all(&folder)?;
+        Ok(folder.join(format!(
+            "{}-{}",
+            ::std::process::id(),
+            NEXT_UPLOAD.fetch_add(1, Ordering::SeqCst)
+        )))
+    }
+
+    fn lock_blob(&self, blob_name: &str) -> Result<BlobLock, io::Error> {
+        let folder = self.state_path("locks");
+        fs::create_dir_all(&folder)?;
+        BlobLock::acquire(folder.join(format!("{:x}", md5::compute(blob_name))))
+    }
+
+    // content is staged in the state folder and moved into place once
+    // complete, so readers never see a partly written blob
+    fn put_blob(
+        &self,
+        blob_name: &str,
+        content: &mut Read,
+        precondition: Precondition,
+    ) -> Result<String, StorageError> {
+        let name = blob_key(blob_name);
+        let path = self.blob_path(&name);
+        let staging_path = self.staging_path()?;
+
+        let mut reader = HashingReader::new(content);
+        let result = File::create(&staging_path)
+            .and_then(|mut file| io::copy(&mut reader, &mut file).and_then(|_| file.flush()));
+        if let Err(e) = result {
+            let _ = fs::remove_file(&staging_path);
+            return Err(StorageError::from(e));
+        }
+
+        // the blob can't change between checking it and replacing it
+        let _lock = match self.lock_blob(&name) {
+            Ok(lock) => lock,
+            Err(e) => {
+                let _ = fs::remove_file(&staging_path);
+                return Err(StorageError::from(e));
+            }
+        };
+        let current_etag = self.current_etag(&name, &path)?;
+        let condition_met = match precondition {
+            Precondition::Overwrite => true,
+            Precondition::IfMatch(etag) => current_etag.as_ref().map(|e| e.as_str()) == Some(etag),
+            Precondition::IfAbsent => current_etag.is_none(),
+        };
+        if !condition_met {
+            let _ = fs::remove_file(&staging_path);
+            return Err(StorageError::ConditionNotMet);
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&staging_path, &path)?;
+
+        let md5 = reader.hash();
+        self.write_md5(&name, &fs::metadata(&path)?, &md5)?;
+        Ok(md5_etag(&md5))
+    }
+
+    fn list_folder(
+        &self,
+        folder: &Path,
+        prefix: &str,
+        blobs: &mut Vec<BlobInfo>,
+    ) -> io::Result<()> {
+        for entry in fs::read_dir(folder)? {
+            let path = entry?.path();
+            let metadata = fs::metadata(&path)?;
+            let name = self.blob_name(&path);
+            if metadata.is_dir() {
+                if path != self.folder.join(sync_state::STATE_FOLDER) {
+                    self.list_folder(&path, prefix, blobs)?;
+                }
+            } else if name.starts_with(prefix) {
+                let md5 = self.blob_md5(&name, &path)?;
+                blobs.push(BlobInfo {
+                    etag: md5_etag(&md5),
+                    content_md5: Some(md5),
+                    name,
+                    last_modified: DateTime::<Utc>::from(metadata.modified()?),
+                    content_length: metadata.len(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn blob_name(&self, path: &Path) -> String {
+        path.strip_prefix(&self.folder)
+            .unwrap_or(path)
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect::<Vec<String>>()
+            .join("/")
+    }
+
+    // folders don't exist in blob storage, so ones left empty are removed
+    fn remove_empty_folders(&self, path: &Path) {
+        let mut folder = path.parent();
+        while let Some(f) = folder {
+            if f == self.folder || fs::remove_dir(f).is_err() {
+                break;
+            }
+            folder = f.parent();
+        }
+    }
+}
+
+// A file that exists only while one writer is replacing a blob. The storage
+// folder may be shared, so other processes are kept out as well as threads.
+struct BlobLock {
+    path: PathBuf,
+}
+
+impl BlobLock {
+    fn acquire(path: PathBuf) -> Result<BlobLock, io::Error> {
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(BlobLock { path }),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    if is_stale(&path) {
+                        trace!("Taking over stale lock {:?}", path);
+                        let _ = fs::remove_file(&path);
+                    } else {
+                        thread::sleep(LOCK_RETRY_DELAY);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for BlobLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn is_stale(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map(|modified| {
+            SystemTime::now()
+                .duration_since(modified)
+                .map(|age| age > LOCK_STALE_AFTER)
+                .unwrap_or(false)
+        })
+        .unwrap_or(false)
+}
+
+impl Storage for LocalStorage {
+    fn upload(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        len: u64,
+    ) -> Result<String, StorageError> {
+        trace!("Uploading - {:?}", blob_name);
+        self.put_blob(blob_name, &mut content.take(len), Precondition::Overwrite)
+    }
+
+    fn upload_if_match(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        len: u64,
+        etag: &str,
+    ) -> Result<String, StorageError> {
+        trace!("Uploading - {:?}", blob_name);
+        self.put_blob(
+            blob_name,
+            &mut content.take(len),
+            Precondition::IfMatch(etag),
+        )
+    }
+
+    fn upload_if_absent(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        len: u64,
+    ) -> Result<String, StorageError> {
+        trace!("Uploading - {:?}", blob_name);
+        self.put_blob(blob_name, &mut content.take(len), Precondition::IfAbsent)
+    }
+
+    fn download(&self, blob_name: &str) -> Result<BlobData, StorageError> {
+        trace!("Downloading - {:?}", blob_name);
+
+        let name = blob_key(blob_name);
+        let path = self.blob_path(&name);
+        let file = File::open(&path).map_err(not_found)?;
+        let etag = md5_etag(&self.blob_md5(&name, &path)?);
+
+        Ok(BlobData {
+            content: Box::new(file),
+            etag,
+        })
+    }
+
+    fn delete(&self, blob_name: &str) -> Result<(), StorageError> {
+        trace!("Deleting - {:?}", blob_name);
+
+        let name = blob_key(blob_name);
+        let path = self.blob_path(&name);
+        if !path.is_file() {
+            return Err(StorageError::PathNotFound);
+        }
+        fs::remove_file(&path).map_err(not_found)?;
+        let _ = fs::remove_file(self.md5_path(&name));
+        self.remove_empty_folders(&path);
+        Ok(())
+    }
+
+    fn copy(&self, from_blob_name: &str, to_blob_name: &str) -> Result<String, StorageError> {
+        trace!("Copying - {:?} to {:?}", from_blob_name, to_blob_name);
+
+        let mut source =
+            File::open(self.blob_path(&blob_key(from_blob_name))).map_err(not_found)?;
+        self.put_blob(to_blob_name, &mut source, Precondition::Overwrite)
+    }
+
+    fn list_blobs_page(
+        &self,
+        prefix: &str,
+        marker: Option<&str>,
+    ) -> Result<BlobPage, StorageError> {
+        // the whole folder is listed in one page
+        let mut blobs = Vec::new();
+        self.list_folder(&self.folder, &blob_key(prefix), &mut blobs)?;
+        blobs.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(BlobPage {
+            blobs,
+            next_marker: None,
+        })
+    }
+}
+
+// blob names are percent encoded for use in urls, the files are named with
+// the decoded names just as Azure stores them
+fn blob_key(blob_name: &str) -> String {
+    percent_decode(blob_name.as_bytes())
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+// a file is assumed to be unchanged while its size and modified time are
+fn file_stamp(metadata: &fs::Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos()))
+        .unwrap_or(0);
+    format!("{:x}-{:x}", metadata.len(), modified)
+}
+
+// the hex md5 of the content, which is also what S3 uses for simple uploads
+fn md5_etag(md5: &str) -> String {
+    base64::decode(md5)
+        .unwrap_or_default()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn not_found(error: io::Error) -> StorageError {
+    match error.kind() {
+        io::ErrorKind::NotFound => StorageError::PathNotFound,
+        _ => StorageError::from(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use azure_client::AzureAuth;
+    use reconcile::ReconcilePolicy;
+    use retry::RetryPolicy;
+    use std::env;
+    use std::time::Duration;
+
+    fn test_storage(name: &str) -> LocalStorage {
+        let folder = env::temp_dir().join(format!(
+            "bucket-local-storage-{}-{}",
+            name,
+            ::std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&folder);
+        let config = bucket::Config {
+            root_folder: String::from("/bucket-local-storage-root"),
+            storage_backend: bucket::StorageBackend::Local(folder.to_string_lossy().into_owned()),
+            storage_account: String::from(""),
+            azure_auth: AzureAuth::SharedKey(String::from("")),
+            root_container_name: String::from(""),
+            blob_endpoint: None,
+            poll_interval: Duration::from_secs(60),
+            reconcile_policy: ReconcilePolicy::Download,
+            block_size: 4 * 1024 * 1024,
+            retry_policy: RetryPolicy::default(),
+            workers: 4,
+        };
+        LocalStorage::new(&config, &folder.to_string_lossy()).unwrap()
+    }
+
+    fn upload(storage: &LocalStorage, blob_name: &str, data: &[u8]) -> String {
+        storage
+            .upload(
+                blob_name,
+                &mut io::Cursor::new(data.to_vec()),
+                data.len() as u64,
+            )
+            .unwrap()
+    }
+
+    fn download(storage: &LocalStorage, blob_name: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        storage
+            .download(blob_name)
+            .unwrap()
+            .content
+            .read_to_end(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn test_uploaded_blob_can_be_downloaded() {
+        let storage = test_storage("round-trip");
+
+        let etag = upload(&storage, "folder/my%20file.txt", b"hello");
+
+        assert!(storage.folder.join("folder").join("my file.txt").is_file());
+        assert_eq!(download(&storage, "folder/my%20file.txt"), b"hello");
+        assert_eq!(storage.download("folder/my%20file.txt").unwrap().etag, etag);
+        fs::remove_dir_all(&storage.folder).unwrap();
+    }
+
+    #[test]
+    fn test_listing_includes_md5_and_skips_state_folder() {
+        let storage = test_storage("listing");
+        upload(&storage, "b.txt", b"hello");
+        upload(&storage, "folder/a.txt", b"world");
+
+        let blobs = storage.list_blobs().unwrap();
+
+        assert_eq!(blobs.len(), 2);
+        assert_eq!(blobs[0].name, "b.txt");
+        assert_eq!(blobs[0].content_length, 5);
+        assert_eq!(blobs[0].content_md5, Some(sync_state::hash(b"hello")));
+        assert_eq!(
+            storage.list_folder_blobs("folder").unwrap(),
+            vec!["folder/a.txt"]
+        );
+        fs::remove_dir_all(&storage.folder).unwrap();
+    }
+
+    #[test]
+    fn test_changed_file_is_hashed_again() {
+        let storage = test_storage("changed");
+        upload(&storage, "file.txt", b"hello");
+        fs::write(storage.folder.join("file.txt"), b"changed elsewhere").unwrap();
+
+        let blobs = storage.list_blobs().unwrap();
+
+        assert_eq!(
+            blobs[0].content_md5,
+            Some(sync_state::hash(b"changed elsewhere"))
+        );
+        fs::remove_dir_all(&storage.folder).unwrap();
+    }
+
+    #[test]
+    fn test_etag_follows_content_not_timestamps() {
+        let storage = test_storage("etag");
+        let first = upload(&storage, "file.txt", b"hello");
+
+        // the same size, and quite possibly the same modified time
+        let second = storage
+            .upload_if_match(
+                "file.txt",
+                &mut io::Cursor::new(b"jello".to_vec()),
+                5,
+                &first,
+            )
+            .unwrap();
+
+        assert_eq!(first, "5d41402abc4b2a76b9719d911017c592");
+        assert!(second != first);
+        assert!(storage
+            .upload_if_match(
+                "file.txt",
+                &mut io::Cursor::new(b"hello".to_vec()),
+                5,
+                &first
+            )
+            .is_err());
+        fs::remove_dir_all(&storage.folder).unwrap();
+    }
+
+    #[test]
+    fn test_upload_if_match_fails_when_etag_has_changed() {
+        let storage = test_storage("condition");
+        let etag = upload(&storage, "file.txt", b"hello");
+        fs::write(storage.folder.join("file.txt"), b"changed elsewhere").unwrap();
+
+        let result = storage.upload_if_match("file.txt", &mut io::Cursor::new(vec![1u8]), 1, &etag);
+
+        match result {
+            Err(StorageError::ConditionNotMet) => (),
+            _ => panic!("expected ConditionNotMet"),
+        }
+        assert_eq!(download(&storage, "file.txt"), b"changed elsewhere");
+        fs::remove_dir_all(&storage.folder).unwrap();
+    }
+
+    #[test]
+    fn test_upload_if_absent_fails_when_blob_exists() {
+        let storage = test_storage("if-absent");
+        upload(&storage, "file.txt", b"first");
+
+        let result = storage.upload_if_absent("file.txt", &mut io::Cursor::new(vec![1u8]), 1);
+
+        match result {
+            Err(StorageError::ConditionNotMet) => (),
+            _ => panic!("expected ConditionNotMet"),
+        }
+        assert_eq!(download(&storage, "file.txt"), b"first");
+        fs::remove_dir_all(&storage.folder).unwrap();
+    }
+
+    #[test]
+    fn test_only_one_concurrent_upload_if_absent_succeeds() {
+        let storage = ::std::sync::Arc::new(test_storage("if-absent-race"));
+
+        let writers: Vec<_> = (0..8u8)
+            .map(|i| {
+                let storage = storage.clone();
+                thread::spawn(move || {
+                    storage
+                        .upload_if_absent("file.txt", &mut io::Cursor::new(vec![i]), 1)
+                        .is_ok()
+                })
+            })
+            .collect();
+        let created = writers
+            .into_iter()
+            .map(|writer| writer.join().unwrap())
+            .filter(|&created| created)
+            .count();
+
+        assert_eq!(created, 1);
+        assert!(storage
+            .state_path("locks")
+            .read_dir()
+            .unwrap()
+            .next()
+            .is_none());
+        fs::remove_dir_all(&storage.folder).unwrap();
+    }
+
+    #[test]
+    fn test_missing_blob_is_not_found() {
+        let storage = test_storage("missing");
+
+        match storage.delete("folder") {
+            Err(StorageError::PathNotFound) => (),
+            _ => panic!("expected PathNotFound"),
+        }
+        match storage.download("file.txt") {
+            Err(StorageError::PathNotFound) => (),
+            _ => panic!("expected PathNotFound"),
+        }
+        fs::remove_dir_all(&storage.folder).unwrap();
+    }
+
+    #[test]
+    fn test_delete_removes_empty_folders() {
+        let storage = test_storage("delete");
+        upload(&storage, "folder/sub/file.txt", b"hello");
+
+        storage.delete("folder/sub/file.txt").unwrap();
+
+        assert!(!storage.folder.join("folder").exists());
+        assert!(storage.list_blobs().unwrap().is_empty());
+        fs::remove_dir_all(&storage.folder).unwrap();
+    }
+
+    #[test]
+    fn test_copy_keeps_source() {
+        let storage = test_storage("copy");
+        upload(&storage, "from.txt", b"hello");
+
+        storage.copy("from.txt", "to.txt").unwrap();
+
+        assert_eq!(download(&storage, "from.txt"), b"hello");
+        assert_eq!(download(&storage, "to.txt"), b"hello");
+        fs::remove_dir_all(&storage.folder).unwrap();
+    }
+}
@@ -1,40 +1,31 @@
-#![allow(dead_code)]
-#![allow(unused_variables)]
-
-extern crate azure_sdk_for_rust;
+extern crate bucket;
 extern crate env_logger;
-extern crate futures;
-extern crate hyper;
-extern crate hyper_tls;
-extern crate notify;
-extern crate sentry;
 #[macro_use]
 extern crate log;
-extern crate md5;
-extern crate tokio_core;
-extern crate url;
-#[macro_use]
-extern crate failure;
-
-mod bucket;
-mod event_handlers;
-mod file_system;
-mod storage;
+extern crate sentry;
 
+use bucket::config;
 use sentry::integrations::panic::register_panic_handler;
 use std::borrow::Cow;
 use std::env;
-
-struct Config {
-    root_folder: String,
-}
+use std::process;
 
 fn main() {
     env_logger::init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = match config::config_path(&args).and_then(config::load) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("bucket: {}", e);
+            process::exit(1);
+        }
+    };
+
     sentry_config();
     register_panic_handler();
 
-    bucket::start();
+    bucket::bucket::start(config);
 }
 
 fn sentry_config() {
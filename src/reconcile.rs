@@ -0,0 +1,376 @@
+use super::file_system;
+use super::storage;
+use super::sync_state;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReconcilePolicy {
+    Download,
+    Mirror,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Upload(String),
+    Download(String),
+    DeleteRemote(String),
+    DeleteLocal(String),
+}
+
+pub fn plan(
+    storage: &storage::Storage,
+    file_system: &file_system::FileSystem,
+    sync_state: &sync_state::SyncState,
+    policy: &ReconcilePolicy,
+) -> Result<Vec<Action>, storage::StorageError> {
+    let mut remote: HashMap<String, storage::BlobInfo> = storage
+        .list_blobs()?
+        .into_iter()
+        .map(|b| (file_system.encode_file_name(&b.name), b))
+        .collect();
+    let mut known_names: HashSet<String> = remote.keys().cloned().collect();
+    let mut actions = Vec::new();
+
+    for path in file_system.list_files() {
+        let blob_name = match file_system.get_blob_name(&path) {
+            Ok(blob_name) => blob_name,
+            Err(e) => {
+                trace!("Error reading {:?} - {}", path, e);
+                continue;
+            }
+        };
+        let local = match file_system.get_file_info(&path) {
+            Ok(info) => info,
+            Err(e) => {
+                trace!("Error reading {:?} - {}", path, e);
+                continue;
+            }
+        };
+        let entry = sync_state.get(&blob_name);
+        known_names.insert(blob_name.clone());
+
+        match (remote.remove(&blob_name), entry) {
+            (None, None) => actions.push(Action::Upload(blob_name)),
+            (None, Some(ref entry)) => {
+                if is_local_changed(entry, &local, &path, file_system) {
+                    actions.push(Action::Upload(blob_name));
+                } else {
+                    actions.push(Action::DeleteLocal(blob_name));
+                }
+            }
+            (Some(ref blob), Some(ref entry)) => {
+                let local_changed = is_local_changed(entry, &local, &path, file_system);
+                let remote_changed = blob.etag != entry.etag;
+                match (local_changed, remote_changed) {
+                    (false, false) => (),
+                    (true, false) => actions.push(Action::Upload(blob_name)),
+                    (false, true) => actions.push(Action::Download(blob_name)),
+                    // the download handler keeps the local changes as a conflicted copy
+                    (true, true) => actions.push(Action::Download(blob_name)),
+                }
+            }
+            (Some(ref blob), None) => {
+                if !is_same_content(blob, &local, &path, file_system) {
+                    actions.push(newest(blob_name, blob, &local));
+                }
+            }
+        }
+    }
+
+    // whatever is left only exists remotely
+    for (blob_name, blob) in remote {
+        match (sync_state.get(&blob_name), policy) {
+            (Some(ref entry), _) if entry.etag == blob.etag => {
+                actions.push(Action::DeleteRemote(blob_name))
+            }
+            (Some(_), _) | (None, &ReconcilePolicy::Download) => {
+                actions.push(Action::Download(blob_name))
+            }
+            (None, &ReconcilePolicy::Mirror) => actions.push(Action::DeleteRemote(blob_name)),
+        }
+    }
+
+    // entries for files that are gone on both sides are no longer needed
+    for name in sync_state.names() {
+        if !known_names.contains(&name) {
+            sync_state.remove(&name);
+        }
+    }
+
+    Ok(actions)
+}
+
+fn newest(blob_name: String, blob: &storage::BlobInfo, local: &file_system::FileInfo) -> Action {
+    if local.last_modified > blob.last_modified {
+        Action::Upload(blob_name)
+    } else {
+        Action::Download(blob_name)
+    }
+}
+
+fn is_local_changed(
+    entry: &sync_state::SyncEntry,
+    local: &file_system::FileInfo,
+    path: &PathBuf,
+    file_system: &file_system::FileSystem,
+) -> bool {
+    if entry.local_size == local.size && entry.local_modified == local.last_modified.timestamp() {
+        return false;
+    }
+    match file_system.get_file_hash(path) {
+        Ok(hash) => hash != entry.hash,
+        Err(_) => false,
+    }
+}
+
+fn is_same_content(
+    blob: &storage::BlobInfo,
+    local: &file_system::FileInfo,
+    path: &PathBuf,
+    file_system: &file_system::FileSystem,
+) -> bool {
+    if blob.content_length != local.size {
+        return false;
+    }
+    match blob.content_md5 {
+        Some(ref remote_md5) => match file_system.get_file_hash(path) {
+            Ok(hash) => *remote_md5 == hash,
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, TimeZone, Utc};
+    use test_support::{MemoryFileSystem, MemoryStorage};
+
+    fn time(hour: u32) -> DateTime<Utc> {
+        Utc.ymd(2018, 11, 1).and_hms(hour, 0, 0)
+    }
+
+    fn storage(blobs: Vec<(&str, &[u8], u32)>) -> MemoryStorage {
+        let storage = MemoryStorage::new();
+        for (name, data, hour) in blobs {
+            storage.put_modified(name, data, time(hour));
+        }
+        storage
+    }
+
+    fn file_system(files: Vec<(&str, &[u8], u32)>) -> MemoryFileSystem {
+        let file_system = MemoryFileSystem::new();
+        for (name, data, hour) in files {
+            file_system.put_modified(name, data, time(hour));
+        }
+        file_system
+    }
+
+    #[test]
+    fn test_local_only_file_is_uploaded() {
+        let storage = storage(Vec::new());
+        let file_system = file_system(vec![("a.txt", b"a", 10)]);
+
+        let actions = plan(
+            &storage,
+            &file_system,
+            &sync_state::SyncState::new(),
+            &ReconcilePolicy::Download,
+        )
+        .unwrap();
+
+        assert_eq!(actions, vec![Action::Upload(String::from("a.txt"))]);
+    }
+
+    #[test]
+    fn test_remote_only_blob_is_downloaded() {
+        let storage = storage(vec![("a.txt", b"a", 10)]);
+        let file_system = file_system(Vec::new());
+
+        let actions = plan(
+            &storage,
+            &file_system,
+            &sync_state::SyncState::new(),
+            &ReconcilePolicy::Download,
+        )
+        .unwrap();
+
+        assert_eq!(actions, vec![Action::Download(String::from("a.txt"))]);
+    }
+
+    #[test]
+    fn test_remote_only_blob_is_deleted_when_mirroring() {
+        let storage = storage(vec![("a.txt", b"a", 10)]);
+        let file_system = file_system(Vec::new());
+
+        let actions = plan(
+            &storage,
+            &file_system,
+            &sync_state::SyncState::new(),
+            &ReconcilePolicy::Mirror,
+        )
+        .unwrap();
+
+        assert_eq!(actions, vec![Action::DeleteRemote(String::from("a.txt"))]);
+    }
+
+    #[test]
+    fn test_newer_local_file_is_uploaded() {
+        let storage = storage(vec![("a.txt", b"old", 10)]);
+        let file_system = file_system(vec![("a.txt", b"newer", 11)]);
+
+        let actions = plan(
+            &storage,
+            &file_system,
+            &sync_state::SyncState::new(),
+            &ReconcilePolicy::Download,
+        )
+        .unwrap();
+
+        assert_eq!(actions, vec![Action::Upload(String::from("a.txt"))]);
+    }
+
+    #[test]
+    fn test_newer_remote_blob_is_downloaded() {
+        let storage = storage(vec![("a.txt", b"newer", 11)]);
+        let file_system = file_system(vec![("a.txt", b"old", 10)]);
+
+        let actions = plan(
+            &storage,
+            &file_system,
+            &sync_state::SyncState::new(),
+            &ReconcilePolicy::Download,
+        )
+        .unwrap();
+
+        assert_eq!(actions, vec![Action::Download(String::from("a.txt"))]);
+    }
+
+    #[test]
+    fn test_identical_content_is_left_alone() {
+        let storage = storage(vec![("a.txt", b"same", 11)]);
+        let file_system = file_system(vec![("a.txt", b"same", 10)]);
+
+        let actions = plan(
+            &storage,
+            &file_system,
+            &sync_state::SyncState::new(),
+            &ReconcilePolicy::Mirror,
+        )
+        .unwrap();
+
+        assert!(actions.is_empty());
+    }
+
+    // records the blob as it is stored now as synced with a local file
+    fn synced(
+        state: &sync_state::SyncState,
+        storage: &MemoryStorage,
+        name: &str,
+        data: &[u8],
+        hour: u32,
+    ) {
+        state.set(
+            name,
+            sync_state::SyncEntry {
+                local_modified: time(hour).timestamp(),
+                local_size: data.len() as u64,
+                hash: sync_state::hash(data),
+                etag: storage.etag(name).unwrap_or_default(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_blob_deleted_locally_while_stopped_is_deleted_remotely() {
+        let storage = storage(vec![("a.txt", b"a", 10)]);
+        let file_system = file_system(Vec::new());
+        let state = sync_state::SyncState::new();
+        synced(&state, &storage, "a.txt", b"a", 10);
+
+        let actions = plan(&storage, &file_system, &state, &ReconcilePolicy::Download).unwrap();
+
+        assert_eq!(actions, vec![Action::DeleteRemote(String::from("a.txt"))]);
+    }
+
+    #[test]
+    fn test_blob_deleted_remotely_while_stopped_is_deleted_locally() {
+        let storage = storage(Vec::new());
+        let file_system = file_system(vec![("a.txt", b"a", 10)]);
+        let state = sync_state::SyncState::new();
+        synced(&state, &storage, "a.txt", b"a", 10);
+
+        let actions = plan(&storage, &file_system, &state, &ReconcilePolicy::Download).unwrap();
+
+        assert_eq!(actions, vec![Action::DeleteLocal(String::from("a.txt"))]);
+    }
+
+    #[test]
+    fn test_file_changed_locally_since_sync_is_uploaded() {
+        let storage = storage(vec![("a.txt", b"a", 10)]);
+        let file_system = file_system(vec![("a.txt", b"changed", 9)]);
+        let state = sync_state::SyncState::new();
+        synced(&state, &storage, "a.txt", b"a", 10);
+
+        let actions = plan(&storage, &file_system, &state, &ReconcilePolicy::Download).unwrap();
+
+        assert_eq!(actions, vec![Action::Upload(String::from("a.txt"))]);
+    }
+
+    #[test]
+    fn test_blob_changed_remotely_since_sync_is_downloaded() {
+        let storage = storage(vec![("a.txt", b"a", 10)]);
+        let file_system = file_system(vec![("a.txt", b"a", 10)]);
+        let state = sync_state::SyncState::new();
+        synced(&state, &storage, "a.txt", b"a", 10);
+        storage.put_modified("a.txt", b"changed", time(9));
+
+        let actions = plan(&storage, &file_system, &state, &ReconcilePolicy::Download).unwrap();
+
+        assert_eq!(actions, vec![Action::Download(String::from("a.txt"))]);
+    }
+
+    #[test]
+    fn test_entry_gone_on_both_sides_is_forgotten() {
+        let storage = storage(Vec::new());
+        let file_system = file_system(Vec::new());
+        let state = sync_state::SyncState::new();
+        synced(&state, &storage, "a.txt", b"a", 10);
+
+        let actions = plan(&storage, &file_system, &state, &ReconcilePolicy::Download).unwrap();
+
+        assert!(actions.is_empty());
+        assert_eq!(state.get("a.txt"), None);
+    }
+
+    #[test]
+    fn test_file_changed_on_both_sides_is_downloaded_for_conflict_resolution() {
+        let storage = storage(vec![("a.txt", b"a", 10)]);
+        let file_system = file_system(vec![("a.txt", b"local", 11)]);
+        let state = sync_state::SyncState::new();
+        synced(&state, &storage, "a.txt", b"a", 10);
+        storage.put_modified("a.txt", b"remote", time(9));
+
+        let actions = plan(&storage, &file_system, &state, &ReconcilePolicy::Download).unwrap();
+
+        assert_eq!(actions, vec![Action::Download(String::from("a.txt"))]);
+    }
+
+    #[test]
+    fn test_listed_blob_names_are_encoded_before_comparing() {
+        let storage = storage(vec![("a b.txt", b"a", 10)]);
+        let file_system = file_system(vec![("a b.txt", b"a", 10)]);
+
+        let actions = plan(
+            &storage,
+            &file_system,
+            &sync_state::SyncState::new(),
+            &ReconcilePolicy::Download,
+        )
+        .unwrap();
+
+        assert!(actions.is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+use super::storage;
+use std::collections::HashMap;
+
+#[derive(Debug, PartialEq)]
+pub enum RemoteEvent {
+    Create(String),
+    Update(String),
+    Remove(String),
+}
+
+pub struct RemoteWatcher {
+    snapshot: Option<HashMap<String, storage::BlobInfo>>,
+}
+
+impl RemoteWatcher {
+    pub fn new() -> RemoteWatcher {
+        RemoteWatcher { snapshot: None }
+    }
+
+    pub fn poll(
+        &mut self,
+        storage: &storage::Storage,
+    ) -> Result<Vec<RemoteEvent>, storage::StorageError> {
+        let current: HashMap<String, storage::BlobInfo> = storage
+            .list_blobs()?
+            .into_iter()
+            .map(|b| (b.name.clone(), b))
+            .collect();
+
+        // the first listing only establishes the baseline to compare against
+        let events = match self.snapshot {
+            Some(ref previous) => diff(previous, &current),
+            None => Vec::new(),
+        };
+
+        self.snapshot = Some(current);
+        Ok(events)
+    }
+}
+
+fn diff(
+    previous: &HashMap<String, storage::BlobInfo>,
+    current: &HashMap<String, storage::BlobInfo>,
+) -> Vec<RemoteEvent> {
+    let mut events = Vec::new();
+
+    for (name, blob) in current {
+        match previous.get(name) {
+            None => events.push(RemoteEvent::Create(name.clone())),
+            Some(old) if old.etag != blob.etag || old.last_modified != blob.last_modified => {
+                events.push(RemoteEvent::Update(name.clone()))
+            }
+            Some(_) => (),
+        }
+    }
+
+    for name in previous.keys() {
+        if !current.contains_key(name) {
+            events.push(RemoteEvent::Remove(name.clone()));
+        }
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::Storage;
+    use test_support::MemoryStorage;
+
+    #[test]
+    fn test_first_poll_returns_no_events() {
+        let storage = MemoryStorage::new();
+        storage.put("file.txt", b"one");
+        let mut watcher = RemoteWatcher::new();
+
+        let events = watcher.poll(&storage).unwrap();
+
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_new_blob_returns_create_event() {
+        let storage = MemoryStorage::new();
+        let mut watcher = RemoteWatcher::new();
+        watcher.poll(&storage).unwrap();
+
+        storage.put("file.txt", b"one");
+        let events = watcher.poll(&storage).unwrap();
+
+        assert_eq!(events, vec![RemoteEvent::Create(String::from("file.txt"))]);
+    }
+
+    #[test]
+    fn test_changed_etag_returns_update_event() {
+        let storage = MemoryStorage::new();
+        storage.put("file.txt", b"one");
+        let mut watcher = RemoteWatcher::new();
+        watcher.poll(&storage).unwrap();
+
+        storage.put("file.txt", b"two");
+        let events = watcher.poll(&storage).unwrap();
+
+        assert_eq!(events, vec![RemoteEvent::Update(String::from("file.txt"))]);
+    }
+
+    #[test]
+    fn test_missing_blob_returns_remove_event() {
+        let storage = MemoryStorage::new();
+        storage.put("file.txt", b"one");
+        let mut watcher = RemoteWatcher::new();
+        watcher.poll(&storage).unwrap();
+
+        storage.delete("file.txt").unwrap();
+        let events = watcher.poll(&storage).unwrap();
+
+        assert_eq!(events, vec![RemoteEvent::Remove(String::from("file.txt"))]);
+    }
+
+    #[test]
+    fn test_unchanged_blob_returns_no_events() {
+        let storage = MemoryStorage::new();
+        storage.put("file.txt", b"one");
+        let mut watcher = RemoteWatcher::new();
+        watcher.poll(&storage).unwrap();
+
+        let events = watcher.poll(&storage).unwrap();
+
+        assert!(events.is_empty());
+    }
+}
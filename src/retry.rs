@@ -0,0 +1,346 @@
+use super::file_system::FileContent;
+use super::storage::{BlobData, BlobPage, Storage, StorageError};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::SeekFrom;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // exponential backoff with jitter, so clients that failed together don't
+    // all come back at the same moment, but never sooner than the service asked
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let backoff = self
+            .base_delay
+            .checked_mul(1 << attempt.saturating_sub(1).min(16))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        let half = backoff / 2;
+        let jitter = match duration_millis(half) {
+            0 => Duration::from_millis(0),
+            millis => Duration::from_millis(random() % (millis + 1)),
+        };
+
+        (half + jitter).max(retry_after.unwrap_or_default())
+    }
+}
+
+pub struct RetryingStorage<S> {
+    inner: S,
+    policy: RetryPolicy,
+}
+
+impl<S: Storage> RetryingStorage<S> {
+    pub fn new(inner: S, policy: RetryPolicy) -> RetryingStorage<S> {
+        RetryingStorage { inner, policy }
+    }
+
+    fn retry<T, F>(&self, operation: &str, mut attempt_operation: F) -> Result<T, StorageError>
+    where
+        F: FnMut() -> Result<T, StorageError>,
+    {
+        let mut attempt = 1;
+        loop {
+            match attempt_operation() {
+                Err(ref e) if e.is_retryable() && attempt < self.policy.max_attempts => {
+                    let delay = self.policy.delay(attempt, e.retry_after());
+                    trace!(
+                        "{} failed on attempt {}, retrying in {:?} - {}",
+                        operation,
+                        attempt,
+                        delay,
+                        e
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn retry_upload<F>(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        mut upload: F,
+    ) -> Result<String, StorageError>
+    where
+        F: FnMut(&mut FileContent) -> Result<String, StorageError>,
+    {
+        let start = content.seek(SeekFrom::Current(0))?;
+        self.retry(&format!("Uploading {}", blob_name), || {
+            content.seek(SeekFrom::Start(start))?;
+            upload(content)
+        })
+    }
+}
+
+impl<S: Storage> Storage for RetryingStorage<S> {
+    fn upload(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        len: u64,
+    ) -> Result<String, StorageError> {
+        self.retry_upload(blob_name, content, |content| {
+            self.inner.upload(blob_name, content, len)
+        })
+    }
+
+    fn upload_if_match(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        len: u64,
+        etag: &str,
+    ) -> Result<String, StorageError> {
+        self.retry_upload(blob_name, content, |content| {
+            self.inner.upload_if_match(blob_name, content, len, etag)
+        })
+    }
+
+    fn upload_if_absent(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        len: u64,
+    ) -> Result<String, StorageError> {
+        self.retry_upload(blob_name, content, |content| {
+            self.inner.upload_if_absent(blob_name, content, len)
+        })
+    }
+
+    fn download(&self, blob_name: &str) -> Result<BlobData, StorageError> {
+        self.retry(&format!("Downloading {}", blob_name), || {
+            self.inner.download(blob_name)
+        })
+    }
+
+    fn delete(&self, blob_name: &str) -> Result<(), StorageError> {
+        self.retry(&format!("Deleting {}", blob_name), || {
+            self.inner.delete(blob_name)
+        })
+    }
+
+    fn copy(&self, from_blob_name: &str, to_blob_name: &str) -> Result<String, StorageError> {
+        self.retry(&format!("Copying {}", from_blob_name), || {
+            self.inner.copy(from_blob_name, to_blob_name)
+        })
+    }
+
+    fn list_blobs_page(
+        &self,
+        prefix: &str,
+        marker: Option<&str>,
+    ) -> Result<BlobPage, StorageError> {
+        self.retry("Listing blobs", || {
+            self.inner.list_blobs_page(prefix, marker)
+        })
+    }
+}
+
+fn duration_millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_millis())
+}
+
+// RandomState is seeded randomly, which is plenty for spreading out retries
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::StatusCode;
+    use std::cell::RefCell;
+    use std::io;
+
+    struct FlakyStorage {
+        failures: RefCell<u32>,
+        attempts: RefCell<u32>,
+        uploaded: RefCell<Vec<u8>>,
+        fatal: bool,
+    }
+
+    impl FlakyStorage {
+        fn new(failures: u32) -> FlakyStorage {
+            FlakyStorage {
+                failures: RefCell::new(failures),
+                attempts: RefCell::new(0),
+                uploaded: RefCell::new(Vec::new()),
+                fatal: false,
+            }
+        }
+
+        fn attempt(&self) -> Result<(), StorageError> {
+            *self.attempts.borrow_mut() += 1;
+            if *self.failures.borrow() == 0 {
+                return Ok(());
+            }
+            *self.failures.borrow_mut() -= 1;
+            let status = if self.fatal {
+                StatusCode::FORBIDDEN
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            Err(StorageError::UnexpectedStatus(status, None))
+        }
+    }
+
+    impl Storage for FlakyStorage {
+        fn upload(
+            &self,
+            blob_name: &str,
+            content: &mut FileContent,
+            len: u64,
+        ) -> Result<String, StorageError> {
+            let mut data = Vec::new();
+            content.read_to_end(&mut data)?;
+            *self.uploaded.borrow_mut() = data;
+            self.attempt().map(|_| String::from("etag"))
+        }
+        fn upload_if_match(
+            &self,
+            blob_name: &str,
+            content: &mut FileContent,
+            len: u64,
+            etag: &str,
+        ) -> Result<String, StorageError> {
+            self.upload(blob_name, content, len)
+        }
+        fn upload_if_absent(
+            &self,
+            blob_name: &str,
+            content: &mut FileContent,
+            len: u64,
+        ) -> Result<String, StorageError> {
+            self.upload(blob_name, content, len)
+        }
+        fn download(&self, blob_name: &str) -> Result<BlobData, StorageError> {
+            self.attempt().map(|_| BlobData {
+                content: Box::new(io::empty()),
+                etag: String::from("etag"),
+            })
+        }
+        fn delete(&self, blob_name: &str) -> Result<(), StorageError> {
+            self.attempt()
+        }
+        fn copy(&self, from_blob_name: &str, to_blob_name: &str) -> Result<String, StorageError> {
+            self.attempt().map(|_| String::from("etag"))
+        }
+        fn list_blobs_page(
+            &self,
+            prefix: &str,
+            marker: Option<&str>,
+        ) -> Result<BlobPage, StorageError> {
+            self.attempt().map(|_| BlobPage {
+                blobs: Vec::new(),
+                next_marker: None,
+            })
+        }
+    }
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(0),
+            max_delay: Duration::from_millis(0),
+        }
+    }
+
+    #[test]
+    fn test_transient_failures_are_retried() {
+        let storage = RetryingStorage::new(FlakyStorage::new(2), policy(5));
+
+        assert!(storage.delete("file.txt").is_ok());
+        assert_eq!(*storage.inner.attempts.borrow(), 3);
+    }
+
+    #[test]
+    fn test_gives_up_after_max_attempts() {
+        let storage = RetryingStorage::new(FlakyStorage::new(10), policy(3));
+
+        assert!(storage.delete("file.txt").is_err());
+        assert_eq!(*storage.inner.attempts.borrow(), 3);
+    }
+
+    #[test]
+    fn test_fatal_failures_are_not_retried() {
+        let mut flaky = FlakyStorage::new(1);
+        flaky.fatal = true;
+        let storage = RetryingStorage::new(flaky, policy(5));
+
+        assert!(storage.delete("file.txt").is_err());
+        assert_eq!(*storage.inner.attempts.borrow(), 1);
+    }
+
+    #[test]
+    fn test_retried_upload_sends_content_from_the_start() {
+        let storage = RetryingStorage::new(FlakyStorage::new(1), policy(5));
+        let mut content = io::Cursor::new(vec![1u8, 2, 3]);
+
+        assert!(storage.upload("file.txt", &mut content, 3).is_ok());
+        assert_eq!(*storage.inner.uploaded.borrow(), vec![1u8, 2, 3]);
+    }
+
+    #[test]
+    fn test_listing_is_retried_through_default_methods() {
+        let storage = RetryingStorage::new(FlakyStorage::new(1), policy(5));
+
+        assert!(storage.list_blobs().is_ok());
+    }
+
+    #[test]
+    fn test_delay_grows_with_each_attempt() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        };
+
+        let first = policy.delay(1, None);
+        let fourth = policy.delay(4, None);
+
+        assert!(first >= Duration::from_millis(500) && first <= Duration::from_secs(1));
+        assert!(fourth >= Duration::from_secs(4) && fourth <= Duration::from_secs(8));
+    }
+
+    #[test]
+    fn test_delay_is_capped() {
+        let policy = RetryPolicy {
+            max_attempts: 50,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        };
+
+        assert!(policy.delay(40, None) <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_delay_honours_retry_after() {
+        let policy = RetryPolicy::default();
+
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(30))),
+            Duration::from_secs(30)
+        );
+    }
+}
@@ -1,21 +1,57 @@
+use super::azure_client::{AzureAccount, AzureClient};
 use super::bucket;
-use azure_sdk_for_rust::core::errors::AzureError;
-use azure_sdk_for_rust::core::DeleteSnapshotsMethod;
-use azure_sdk_for_rust::prelude::*;
-use futures::future::*;
-use hyper::StatusCode;
-use std::io;
-use std::path::PathBuf;
-use tokio_core::reactor::Core;
+use super::file_system::FileContent;
+use super::http_client::{
+    check_status, child_text, children, content_length, header_value, parse_xml, Response,
+};
+use super::sync_state;
+use super::upload_journal::UploadJournal;
+use chrono::{DateTime, Utc};
+use futures::Future;
+use hyper::{HeaderMap, Method, StatusCode};
+use std::io::{self, Read};
+use std::path::Path;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+use std::vec;
+use url::percent_encoding::percent_decode;
 
 #[derive(Debug, Fail)]
 pub enum StorageError {
     #[fail(display = "The specified path was not found")]
     PathNotFound,
+    #[fail(display = "The blob has been changed by someone else")]
+    ConditionNotMet,
+    #[fail(display = "The blob copy did not complete - {}", _0)]
+    CopyFailed(String),
     #[fail(display = "An io error has occurred - {:?}", _0)]
     IOError(io::Error),
-    #[fail(display = "An unknown error has occurred - {:?}", _0)]
-    UnknownError(AzureError),
+    #[fail(display = "The uncommitted blocks of {} are no longer available", _0)]
+    BlocksExpired(String),
+    #[fail(display = "The request failed with status {}", _0)]
+    UnexpectedStatus(StatusCode, Option<Duration>),
+    #[fail(display = "A connection error has occurred - {:?}", _0)]
+    ConnectionError(hyper::Error),
+}
+
+impl StorageError {
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            StorageError::ConnectionError(_) => true,
+            // the upload starts again from the first block
+            StorageError::BlocksExpired(_) => true,
+            StorageError::UnexpectedStatus(status, _) => is_transient_status(*status),
+            _ => false,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            StorageError::UnexpectedStatus(_, retry_after) => *retry_after,
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for StorageError {
@@ -24,109 +60,936 @@ impl From<io::Error> for StorageError {
     }
 }
 
-impl From<AzureError> for StorageError {
-    fn from(error: AzureError) -> Self {
-        StorageError::UnknownError(error)
+fn is_transient_status(status: StatusCode) -> bool {
+    match status {
+        StatusCode::REQUEST_TIMEOUT
+        | StatusCode::TOO_MANY_REQUESTS
+        | StatusCode::INTERNAL_SERVER_ERROR
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE
+        | StatusCode::GATEWAY_TIMEOUT => true,
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlobInfo {
+    pub name: String,
+    pub etag: String,
+    pub last_modified: DateTime<Utc>,
+    pub content_length: u64,
+    pub content_md5: Option<String>,
+}
+
+pub struct BlobData {
+    pub content: Box<Read>,
+    pub etag: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlobPage {
+    pub blobs: Vec<BlobInfo>,
+    pub next_marker: Option<String>,
+}
+
+// What the blob being uploaded over must be like for the upload to go ahead
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Precondition<'a> {
+    Overwrite,
+    IfMatch(&'a str),
+    IfAbsent,
+}
+
+impl<'a> Precondition<'a> {
+    // Azure and S3 take the same conditional headers
+    pub fn header(&self) -> Option<(&'static str, String)> {
+        match *self {
+            Precondition::Overwrite => None,
+            Precondition::IfMatch(etag) => Some(("if-match", format!("\"{}\"", etag))),
+            Precondition::IfAbsent => Some(("if-none-match", String::from("*"))),
+        }
     }
 }
 
 pub trait Storage {
-    fn upload(&self, &str, Vec<u8>) -> Result<(), StorageError>;
-    fn download(&self, &PathBuf);
+    fn upload(&self, &str, &mut FileContent, u64) -> Result<String, StorageError>;
+    fn upload_if_match(&self, &str, &mut FileContent, u64, &str) -> Result<String, StorageError>;
+    fn upload_if_absent(&self, &str, &mut FileContent, u64) -> Result<String, StorageError>;
+    fn download(&self, &str) -> Result<BlobData, StorageError>;
     fn delete(&self, &str) -> Result<(), StorageError>;
-    fn list_folder_blobs(&self, &str) -> Result<Vec<String>, StorageError>;
+    fn copy(&self, &str, &str) -> Result<String, StorageError>;
+    fn list_blobs_page(&self, &str, Option<&str>) -> Result<BlobPage, StorageError>;
+
+    fn list_folder_blobs(&self, folder_name: &str) -> Result<Vec<String>, StorageError> {
+        let prefix = format!("{}/", folder_name);
+        let blobs = collect_pages(|marker| self.list_blobs_page(&prefix, marker))?;
+        Ok(blobs.into_iter().map(|b| b.name).collect())
+    }
+
+    fn list_blobs(&self) -> Result<Vec<BlobInfo>, StorageError> {
+        collect_pages(|marker| self.list_blobs_page("", marker))
+    }
+}
+
+impl<S: Storage + ?Sized> Storage for Box<S> {
+    fn upload(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        len: u64,
+    ) -> Result<String, StorageError> {
+        (**self).upload(blob_name, content, len)
+    }
+
+    fn upload_if_match(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        len: u64,
+        etag: &str,
+    ) -> Result<String, StorageError> {
+        (**self).upload_if_match(blob_name, content, len, etag)
+    }
+
+    fn upload_if_absent(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        len: u64,
+    ) -> Result<String, StorageError> {
+        (**self).upload_if_absent(blob_name, content, len)
+    }
+
+    fn download(&self, blob_name: &str) -> Result<BlobData, StorageError> {
+        (**self).download(blob_name)
+    }
+
+    fn delete(&self, blob_name: &str) -> Result<(), StorageError> {
+        (**self).delete(blob_name)
+    }
+
+    fn copy(&self, from_blob_name: &str, to_blob_name: &str) -> Result<String, StorageError> {
+        (**self).copy(from_blob_name, to_blob_name)
+    }
+
+    fn list_blobs_page(
+        &self,
+        prefix: &str,
+        marker: Option<&str>,
+    ) -> Result<BlobPage, StorageError> {
+        (**self).list_blobs_page(prefix, marker)
+    }
+}
+
+pub type StorageFuture<T> = Box<Future<Item = T, Error = StorageError>>;
+
+// Storage operations that don't block, so several requests can be in flight
+// on one reactor. The futures only make progress inside run, so combine them
+// first and run the result. Uploads take the whole content, so are only
+// suitable for blobs small enough to hold in memory.
+pub trait AsyncStorage {
+    fn upload_async(&self, &str, Vec<u8>, Precondition) -> StorageFuture<String>;
+    fn download_async(&self, &str) -> StorageFuture<BlobData>;
+    fn delete_async(&self, &str) -> StorageFuture<()>;
+    fn list_blobs_page_async(&self, &str, Option<&str>) -> StorageFuture<BlobPage>;
+    fn run<T>(&self, StorageFuture<T>) -> Result<T, StorageError>;
+}
+
+// Lists blobs a page at a time, following continuation markers, so large
+// folders can be processed without holding every name in memory.
+pub struct BlobListing<'a> {
+    storage: &'a Storage,
+    prefix: String,
+    page: vec::IntoIter<BlobInfo>,
+    next_marker: Option<String>,
+    finished: bool,
+}
+
+impl<'a> BlobListing<'a> {
+    pub fn new(storage: &'a Storage, prefix: &str) -> BlobListing<'a> {
+        BlobListing {
+            storage,
+            prefix: String::from(prefix),
+            page: Vec::new().into_iter(),
+            next_marker: None,
+            finished: false,
+        }
+    }
+
+    pub fn folder(storage: &'a Storage, folder_name: &str) -> BlobListing<'a> {
+        BlobListing::new(storage, &format!("{}/", folder_name))
+    }
+}
+
+impl<'a> Iterator for BlobListing<'a> {
+    type Item = Result<BlobInfo, StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(blob) = self.page.next() {
+                return Some(Ok(blob));
+            }
+            if self.finished {
+                return None;
+            }
+
+            let marker = self.next_marker.take();
+            match self
+                .storage
+                .list_blobs_page(&self.prefix, marker.as_ref().map(|m| m.as_str()))
+            {
+                Ok(page) => {
+                    self.finished = page.next_marker.is_none();
+                    self.next_marker = page.next_marker;
+                    self.page = page.blobs.into_iter();
+                }
+                Err(e) => {
+                    self.finished = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+fn collect_pages<F>(list_page: F) -> Result<Vec<BlobInfo>, StorageError>
+where
+    F: Fn(Option<&str>) -> Result<BlobPage, StorageError>,
+{
+    let mut blobs = Vec::new();
+    let mut next_marker: Option<String> = None;
+
+    loop {
+        let page = list_page(next_marker.as_ref().map(|m| m.as_str()))?;
+        blobs.extend(page.blobs);
+        next_marker = page.next_marker;
+        if next_marker.is_none() {
+            return Ok(blobs);
+        }
+    }
 }
 
+// Azure allows at most 50,000 blocks in a block blob
+const MAX_BLOCKS: usize = 50_000;
+
 pub struct AzureStorage {
     pub storage_account: String,
-    pub account_key: String,
     pub root_container_name: String,
+    pub block_size: usize,
+    upload_journal: UploadJournal,
+    // left to the service unless a test needs listings split into pages
+    list_page_size: Option<u32>,
+    // one client for every request, so connections are kept alive and
+    // reused rather than paying for a TLS handshake each time
+    client: Rc<AzureClient>,
 }
 
 impl Storage for AzureStorage {
-    fn upload(&self, blob_name: &str, data: Vec<u8>) -> Result<(), StorageError> {
-        trace!("Uploading - {:?}", blob_name);
+    fn upload(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        len: u64,
+    ) -> Result<String, StorageError> {
+        self.put_blob(blob_name, content, len, Precondition::Overwrite)
+    }
 
-        let mut core = Core::new()?;
-        let client = Client::new(&self.storage_account, &self.account_key)?;
+    fn upload_if_match(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        len: u64,
+        etag: &str,
+    ) -> Result<String, StorageError> {
+        self.put_blob(blob_name, content, len, Precondition::IfMatch(etag))
+    }
 
-        let digest = md5::compute(&data[..]);
+    fn upload_if_absent(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        len: u64,
+    ) -> Result<String, StorageError> {
+        self.put_blob(blob_name, content, len, Precondition::IfAbsent)
+    }
 
-        let future = client
-            .put_block_blob()
-            .with_container_name(&self.root_container_name)
-            .with_blob_name(blob_name)
-            .with_body(&data[..])
-            .with_content_md5(&digest[..])
-            .finalize();
+    fn download(&self, blob_name: &str) -> Result<BlobData, StorageError> {
+        self.client.run(self.download_async(blob_name))
+    }
 
-        core.run(future)?;
+    fn delete(&self, blob_name: &str) -> Result<(), StorageError> {
+        self.client.run(self.delete_async(blob_name))
+    }
 
-        Ok(())
+    fn copy(&self, from_blob_name: &str, to_blob_name: &str) -> Result<String, StorageError> {
+        trace!("Copying - {:?} to {:?}", from_blob_name, to_blob_name);
+
+        let (headers, _) = self.client.send(
+            Method::PUT,
+            Some(to_blob_name),
+            &[],
+            &[("x-ms-copy-source", self.client.blob_url(from_blob_name))],
+            Vec::new(),
+            StatusCode::ACCEPTED,
+        )?;
+
+        let mut copy_status = header_value(&headers, "x-ms-copy-status");
+        let mut etag = header_value(&headers, "etag");
+
+        // copies within an account usually complete straight away, but large
+        // blobs can still be pending and the source must outlive the copy
+        while copy_status == "pending" {
+            thread::sleep(Duration::from_secs(1));
+            let (headers, _) = self.client.send(
+                Method::HEAD,
+                Some(to_blob_name),
+                &[],
+                &[],
+                Vec::new(),
+                StatusCode::OK,
+            )?;
+            copy_status = header_value(&headers, "x-ms-copy-status");
+            etag = header_value(&headers, "etag");
+        }
+
+        if copy_status != "success" {
+            return Err(StorageError::CopyFailed(copy_status));
+        }
+
+        Ok(etag)
     }
 
-    fn download(&self, p: &PathBuf) {
-        trace!("Downloading - {:?}", p);
+    fn list_blobs_page(
+        &self,
+        prefix: &str,
+        marker: Option<&str>,
+    ) -> Result<BlobPage, StorageError> {
+        self.client.run(self.list_blobs_page_async(prefix, marker))
     }
+}
 
-    fn delete(&self, blob_name: &str) -> Result<(), StorageError> {
+impl AsyncStorage for AzureStorage {
+    fn upload_async(
+        &self,
+        blob_name: &str,
+        data: Vec<u8>,
+        precondition: Precondition,
+    ) -> StorageFuture<String> {
+        trace!("Uploading - {:?}", blob_name);
+
+        let mut headers = vec![
+            ("x-ms-blob-type", String::from("BlockBlob")),
+            ("content-md5", base64::encode(&md5::compute(&data[..])[..])),
+        ];
+        headers.extend(precondition.header());
+        let creating = precondition == Precondition::IfAbsent;
+
+        let description = format!("Uploading {}", blob_name);
+        Box::new(
+            self.client
+                .request(Method::PUT, Some(blob_name), &[], &headers, data)
+                .and_then(move |response| check_status(&description, response, StatusCode::CREATED))
+                .map(|(headers, _)| header_value(&headers, "etag"))
+                .map_err(move |e| if creating { already_exists(e) } else { e }),
+        )
+    }
+
+    fn download_async(&self, blob_name: &str) -> StorageFuture<BlobData> {
+        trace!("Downloading - {:?}", blob_name);
+
+        let client = self.client.clone();
+        let blob_name = String::from(blob_name);
+        let chunk_size = self.block_size as u64;
+        let description = format!("Downloading {}", blob_name);
+
+        Box::new(
+            self.client
+                .request(Method::HEAD, Some(&blob_name), &[], &[], Vec::new())
+                .and_then(move |response| check_status(&description, response, StatusCode::OK))
+                .and_then(move |(headers, _)| {
+                    let etag = header_value(&headers, "etag");
+                    let content = BlobReader {
+                        client,
+                        blob_name,
+                        etag: etag.clone(),
+                        position: 0,
+                        length: content_length(&headers)?,
+                        chunk_size,
+                        chunk: io::Cursor::new(Vec::new()),
+                    };
+
+                    Ok(BlobData {
+                        content: Box::new(content),
+                        etag,
+                    })
+                }),
+        )
+    }
+
+    fn delete_async(&self, blob_name: &str) -> StorageFuture<()> {
         trace!("Deleting - {:?}", blob_name);
 
-        let mut core = Core::new()?;
-        let client = Client::new(&self.storage_account, &self.account_key)?;
+        let description = format!("Deleting {}", blob_name);
+        Box::new(
+            self.client
+                .request(
+                    Method::DELETE,
+                    Some(blob_name),
+                    &[],
+                    &[("x-ms-delete-snapshots", String::from("include"))],
+                    Vec::new(),
+                )
+                .and_then(move |response| {
+                    check_status(&description, response, StatusCode::ACCEPTED)
+                })
+                .map(|_| ()),
+        )
+    }
+
+    fn list_blobs_page_async(&self, prefix: &str, marker: Option<&str>) -> StorageFuture<BlobPage> {
+        let page_size = self.list_page_size.map(|s| s.to_string());
+        // the prefix is encoded along with the rest of the query
+        let prefix = percent_decode(prefix.as_bytes()).decode_utf8_lossy();
+        let mut query = vec![("restype", "container"), ("comp", "list")];
+        if !prefix.is_empty() {
+            query.push(("prefix", &prefix));
+        }
+        if let Some(marker) = marker {
+            query.push(("marker", marker));
+        }
+        if let Some(ref page_size) = page_size {
+            query.push(("maxresults", page_size));
+        }
+
+        Box::new(
+            self.client
+                .request(Method::GET, None, &query, &[], Vec::new())
+                .and_then(|response| check_status("Listing blobs", response, StatusCode::OK))
+                .and_then(|(_, body)| {
+                    parse_blob_list(&body).ok_or_else(|| {
+                        StorageError::from(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Unexpected response to List Blobs",
+                        ))
+                    })
+                }),
+        )
+    }
+
+    fn run<T>(&self, future: StorageFuture<T>) -> Result<T, StorageError> {
+        self.client.run(future)
+    }
+}
+
+impl AzureStorage {
+    pub fn new(config: &bucket::Config) -> Result<AzureStorage, StorageError> {
+        // a connection string can name the account itself
+        let account = AzureAccount::new(
+            &config.storage_account,
+            config.blob_endpoint.as_ref().map(|e| e.as_str()),
+            &config.azure_auth,
+        )?;
+
+        Ok(AzureStorage {
+            storage_account: account.name.clone(),
+            root_container_name: config.root_container_name.clone(),
+            block_size: config.block_size,
+            upload_journal: UploadJournal::new(
+                Path::new(&config.root_folder)
+                    .join(sync_state::STATE_FOLDER)
+                    .join("uploads"),
+            ),
+            list_page_size: None,
+            client: Rc::new(AzureClient::new(account, &config.root_container_name)?),
+        })
+    }
+
+    // smaller pages make listings follow continuation markers
+    pub fn with_list_page_size(self, list_page_size: u32) -> AzureStorage {
+        AzureStorage {
+            list_page_size: Some(list_page_size),
+            ..self
+        }
+    }
+
+    fn put_blob(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        len: u64,
+        precondition: Precondition,
+    ) -> Result<String, StorageError> {
+        if len > self.block_size as u64 {
+            trace!("Uploading - {:?}", blob_name);
+            return self.put_blocks(blob_name, content, len as usize, precondition);
+        }
+
+        let mut data = Vec::new();
+        content.take(len).read_to_end(&mut data)?;
 
-        let future = client
-            .delete_blob()
-            .with_container_name(&self.root_container_name)
-            .with_blob_name(blob_name)
-            .with_delete_snapshots_method(DeleteSnapshotsMethod::Include)
-            .finalize();
+        self.client
+            .run(self.upload_async(blob_name, data, precondition))
+    }
+
+    fn put_blocks(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        len: usize,
+        precondition: Precondition,
+    ) -> Result<String, StorageError> {
+        let block_size = effective_block_size(len, self.block_size);
+        let blocks = split_into_blocks(len, block_size);
+        let uploaded = self.upload_journal.uploaded_blocks(blob_name, block_size);
+        if uploaded.is_empty() {
+            self.upload_journal.start(blob_name, block_size);
+        }
+        trace!(
+            "Uploading {} blocks, {} already uploaded - {:?}",
+            blocks.len(),
+            uploaded.len(),
+            blob_name
+        );
 
-        let result = core.run(future);
+        // only one block is held in memory at a time
+        let mut buffer = vec![0; block_size];
+        for (index, block) in blocks.iter().enumerate() {
+            let body = &mut buffer[..block.end - block.start];
+            content.read_exact(body)?;
+            let digest = md5::compute(&body[..]);
+            let md5 = base64::encode(&digest[..]);
+
+            if uploaded.get(&index) == Some(&md5) {
+                continue;
+            }
+
+            let block_id = base64::encode(block.id.as_bytes());
+            if let Err(e) = self.client.send(
+                Method::PUT,
+                Some(blob_name),
+                &[("comp", "block"), ("blockid", &block_id)],
+                &[("content-md5", md5.clone())],
+                body.to_vec(),
+                StatusCode::CREATED,
+            ) {
+                trace!(
+                    "Error uploading block {} of {} - {:?}",
+                    block.id,
+                    blob_name,
+                    e
+                );
+                return Err(e);
+            }
+            self.upload_journal.record_block(blob_name, index, &md5);
+        }
+
+        let block_list: String = blocks
+            .iter()
+            .map(|b| {
+                format!(
+                    "<Uncommitted>{}</Uncommitted>",
+                    base64::encode(b.id.as_bytes())
+                )
+            })
+            .collect();
+        let headers: Vec<_> = precondition.header().into_iter().collect();
+
+        let result = self
+            .client
+            .run(
+                self.client.request(
+                    Method::PUT,
+                    Some(blob_name),
+                    &[("comp", "blocklist")],
+                    &headers,
+                    format!(
+                        "<?xml version=\"1.0\" encoding=\"utf-8\"?><BlockList>{}</BlockList>",
+                        block_list
+                    )
+                    .into_bytes(),
+                ),
+            )
+            .and_then(|response| check_block_list_status(blob_name, response));
 
         match result {
-            Err(AzureError::UnexpectedHTTPResult(ref h))
-                if h.status_code() == StatusCode::NOT_FOUND =>
-            {
-                Err(StorageError::PathNotFound)
+            Err(e @ StorageError::BlocksExpired(_)) => {
+                // the uncommitted blocks have expired, so start again on the next attempt
+                self.upload_journal.finish(blob_name);
+                Err(e)
             }
             Err(e) => {
-                trace!("Error deleting {} - {:?}", blob_name, e);
-                Err(StorageError::UnknownError(e))
+                trace!("Error committing blocks of {} - {:?}", blob_name, e);
+                if precondition == Precondition::IfAbsent {
+                    Err(already_exists(e))
+                } else {
+                    Err(e)
+                }
+            }
+            Ok((headers, _)) => {
+                self.upload_journal.finish(blob_name);
+                Ok(header_value(&headers, "etag"))
             }
-            Ok(_) => Ok(()),
         }
     }
+}
 
-    fn list_folder_blobs(&self, blob_name: &str) -> Result<Vec<String>, StorageError> {
-        let mut blobs = Vec::<String>::new();
-        let folder_name = format!("{}/", blob_name);
-        let mut core = Core::new()?;
-        let client = Client::new(&self.storage_account, &self.account_key)?;
+// Only a block list naming blocks Azure no longer has means they expired, any
+// other bad request is reported like the rest.
+fn check_block_list_status(
+    blob_name: &str,
+    response: Response,
+) -> Result<(HeaderMap, Vec<u8>), StorageError> {
+    let (status, headers, body) = response;
+    if status == StatusCode::BAD_REQUEST
+        && header_value(&headers, "x-ms-error-code") == "InvalidBlockList"
+    {
+        return Err(StorageError::BlocksExpired(String::from(blob_name)));
+    }
+    let description = format!("Committing blocks of {}", blob_name);
+    check_status(&description, (status, headers, body), StatusCode::CREATED)
+}
 
-        let future = client
-            .list_blobs()
-            .with_container_name(&self.root_container_name)
-            .with_prefix(&folder_name)
-            .finalize()
-            .map(|iv| {
-                for blob in iv.incomplete_vector.iter() {
-                    blobs.push(blob.name.clone());
-                }
-                blobs
-            });
+// Azure refuses to create a blob that already exists with 409 rather than 412
+fn already_exists(error: StorageError) -> StorageError {
+    match error {
+        StorageError::UnexpectedStatus(StatusCode::CONFLICT, _) => StorageError::ConditionNotMet,
+        e => e,
+    }
+}
+
+// Fetches a blob one range at a time as it is read, so only a single chunk
+// is ever held in memory.
+struct BlobReader {
+    client: Rc<AzureClient>,
+    blob_name: String,
+    etag: String,
+    position: u64,
+    length: u64,
+    chunk_size: u64,
+    chunk: io::Cursor<Vec<u8>>,
+}
+
+impl BlobReader {
+    fn fetch_chunk(&mut self) -> Result<(), StorageError> {
+        let end = (self.position + self.chunk_size).min(self.length);
+        let (_, body) = self.client.send(
+            Method::GET,
+            Some(&self.blob_name),
+            &[],
+            &[
+                ("x-ms-range", format!("bytes={}-{}", self.position, end - 1)),
+                ("if-match", format!("\"{}\"", self.etag)),
+            ],
+            Vec::new(),
+            StatusCode::PARTIAL_CONTENT,
+        )?;
 
-        let blobs = core.run(future)?;
-        Ok(blobs)
+        self.chunk = io::Cursor::new(body);
+        self.position = end;
+        Ok(())
     }
 }
 
-impl AzureStorage {
-    pub fn new(config: &bucket::Config) -> AzureStorage {
-        AzureStorage {
-            storage_account: config.storage_account.clone(),
-            account_key: config.account_key.clone(),
-            root_container_name: config.root_container_name.clone(),
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.chunk.position() as usize >= self.chunk.get_ref().len() {
+            if self.position >= self.length {
+                return Ok(0);
+            }
+            if let Err(e) = self.fetch_chunk() {
+                trace!("Error downloading {} - {}", self.blob_name, e);
+                return Err(io::Error::new(io::ErrorKind::Other, e.to_string()));
+            }
+        }
+        self.chunk.read(buf)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Block {
+    id: String,
+    start: usize,
+    end: usize,
+}
+
+// grow the blocks if the file would otherwise need too many of them
+fn effective_block_size(len: usize, block_size: usize) -> usize {
+    block_size.max((len + MAX_BLOCKS - 1) / MAX_BLOCKS).max(1)
+}
+
+fn split_into_blocks(len: usize, block_size: usize) -> Vec<Block> {
+    (0..len)
+        .step_by(block_size)
+        .enumerate()
+        .map(|(i, start)| Block {
+            // block ids must all be the same length within a blob
+            id: format!("{:08}", i),
+            start,
+            end: (start + block_size).min(len),
+        })
+        .collect()
+}
+
+fn parse_blob_list(body: &[u8]) -> Option<BlobPage> {
+    let result = parse_xml(body)?;
+    let mut blobs = Vec::new();
+    for list in children(&result, "Blobs") {
+        for blob in children(list, "Blob") {
+            let properties = children(blob, "Properties").next()?;
+            blobs.push(BlobInfo {
+                name: child_text(blob, "Name")?,
+                etag: child_text(properties, "Etag")?.trim_matches('"').to_owned(),
+                last_modified: DateTime::parse_from_rfc2822(&child_text(
+                    properties,
+                    "Last-Modified",
+                )?)
+                .ok()?
+                .with_timezone(&Utc),
+                content_length: child_text(properties, "Content-Length")?.parse().ok()?,
+                content_md5: child_text(properties, "Content-MD5").filter(|m| !m.is_empty()),
+            });
+        }
+    }
+
+    Some(BlobPage {
+        blobs,
+        next_marker: child_text(&result, "NextMarker").filter(|m| !m.is_empty()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use std::cell::RefCell;
+
+    struct PagedStorage {
+        blobs: Vec<String>,
+        page_size: usize,
+        pages_listed: RefCell<u32>,
+    }
+
+    impl PagedStorage {
+        fn new(blobs: Vec<&str>, page_size: usize) -> PagedStorage {
+            PagedStorage {
+                blobs: blobs.into_iter().map(String::from).collect(),
+                page_size,
+                pages_listed: RefCell::new(0),
+            }
+        }
+    }
+
+    impl Storage for PagedStorage {
+        fn upload(
+            &self,
+            blob_name: &str,
+            content: &mut FileContent,
+            len: u64,
+        ) -> Result<String, StorageError> {
+            Ok(String::from("etag"))
+        }
+        fn upload_if_match(
+            &self,
+            blob_name: &str,
+            content: &mut FileContent,
+            len: u64,
+            etag: &str,
+        ) -> Result<String, StorageError> {
+            Ok(String::from("etag"))
+        }
+        fn upload_if_absent(
+            &self,
+            blob_name: &str,
+            content: &mut FileContent,
+            len: u64,
+        ) -> Result<String, StorageError> {
+            Ok(String::from("etag"))
+        }
+        fn download(&self, blob_name: &str) -> Result<BlobData, StorageError> {
+            Err(StorageError::PathNotFound)
+        }
+        fn delete(&self, blob_name: &str) -> Result<(), StorageError> {
+            Ok(())
         }
+        fn copy(&self, from_blob_name: &str, to_blob_name: &str) -> Result<String, StorageError> {
+            Ok(String::from("etag"))
+        }
+        fn list_blobs_page(
+            &self,
+            prefix: &str,
+            marker: Option<&str>,
+        ) -> Result<BlobPage, StorageError> {
+            *self.pages_listed.borrow_mut() += 1;
+            let matching: Vec<&String> = self
+                .blobs
+                .iter()
+                .filter(|b| b.starts_with(prefix))
+                .collect();
+            let start: usize = marker.map(|m| m.parse().unwrap()).unwrap_or(0);
+            let end = (start + self.page_size).min(matching.len());
+
+            Ok(BlobPage {
+                blobs: matching[start..end]
+                    .iter()
+                    .map(|name| BlobInfo {
+                        name: (*name).clone(),
+                        etag: String::from("etag"),
+                        last_modified: Utc::now(),
+                        content_length: 0,
+                        content_md5: None,
+                    })
+                    .collect(),
+                next_marker: if end < matching.len() {
+                    Some(end.to_string())
+                } else {
+                    None
+                },
+            })
+        }
+    }
+
+    fn bad_request(error_code: &str) -> Response {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ms-error-code", error_code.parse().unwrap());
+        (StatusCode::BAD_REQUEST, headers, Vec::new())
+    }
+
+    #[test]
+    fn test_only_invalid_block_list_means_blocks_expired() {
+        match check_block_list_status("a.bin", bad_request("InvalidBlockList")) {
+            Err(StorageError::BlocksExpired(ref name)) if name == "a.bin" => (),
+            r => panic!("expected BlocksExpired, got {:?}", r),
+        }
+        match check_block_list_status("a.bin", bad_request("InvalidHeaderValue")) {
+            Err(StorageError::UnexpectedStatus(StatusCode::BAD_REQUEST, _)) => (),
+            r => panic!("expected UnexpectedStatus, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_split_into_blocks_covers_all_data() {
+        let blocks = split_into_blocks(10, 4);
+
+        assert_eq!(
+            blocks,
+            vec![
+                Block {
+                    id: String::from("00000000"),
+                    start: 0,
+                    end: 4,
+                },
+                Block {
+                    id: String::from("00000001"),
+                    start: 4,
+                    end: 8,
+                },
+                Block {
+                    id: String::from("00000002"),
+                    start: 8,
+                    end: 10,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_into_blocks_respects_block_limit() {
+        let blocks = split_into_blocks(MAX_BLOCKS * 10, effective_block_size(MAX_BLOCKS * 10, 1));
+
+        assert_eq!(blocks.len(), MAX_BLOCKS);
+        assert_eq!(blocks.last().unwrap().end, MAX_BLOCKS * 10);
+    }
+
+    #[test]
+    fn test_list_folder_blobs_follows_continuation_markers() {
+        let storage = PagedStorage::new(vec!["a/1", "a/2", "a/3", "a/4", "a/5", "b/1"], 2);
+
+        let blobs = storage.list_folder_blobs("a").unwrap();
+
+        assert_eq!(blobs, vec!["a/1", "a/2", "a/3", "a/4", "a/5"]);
+        assert_eq!(*storage.pages_listed.borrow(), 3);
+    }
+
+    #[test]
+    fn test_list_blobs_lists_whole_container() {
+        let storage = PagedStorage::new(vec!["a/1", "a/2", "b/1"], 2);
+
+        let blobs = storage.list_blobs().unwrap();
+
+        assert_eq!(blobs.len(), 3);
+    }
+
+    #[test]
+    fn test_blob_listing_fetches_pages_lazily() {
+        let storage = PagedStorage::new(vec!["a/1", "a/2", "a/3", "a/4", "a/5"], 2);
+
+        let first: Vec<String> = BlobListing::folder(&storage, "a")
+            .take(2)
+            .map(|b| b.unwrap().name)
+            .collect();
+
+        assert_eq!(first, vec!["a/1", "a/2"]);
+        assert_eq!(*storage.pages_listed.borrow(), 1);
+    }
+
+    #[test]
+    fn test_blob_listing_of_empty_folder() {
+        let storage = PagedStorage::new(vec!["b/1"], 2);
+
+        assert_eq!(BlobListing::folder(&storage, "a").count(), 0);
+    }
+
+    #[test]
+    fn test_parse_blob_list() {
+        let body = br#"<?xml version="1.0" encoding="utf-8"?>
+            <EnumerationResults ServiceEndpoint="https://account.blob.core.windows.net/" ContainerName="container">
+              <MaxResults>2</MaxResults>
+              <Blobs>
+                <Blob>
+                  <Name>folder/my file.txt</Name>
+                  <Properties>
+                    <Last-Modified>Tue, 04 Sep 2018 10:00:00 GMT</Last-Modified>
+                    <Etag>0x8D612B5F2A8C2B1</Etag>
+                    <Content-Length>5</Content-Length>
+                    <Content-MD5>XUFAKrxLKna5cZ2REBfFkg==</Content-MD5>
+                  </Properties>
+                </Blob>
+                <Blob>
+                  <Name>large.bin</Name>
+                  <Properties>
+                    <Last-Modified>Tue, 04 Sep 2018 11:00:00 GMT</Last-Modified>
+                    <Etag>0x8D612B5F2A8C2B2</Etag>
+                    <Content-Length>3000</Content-Length>
+                    <Content-MD5 />
+                  </Properties>
+                </Blob>
+              </Blobs>
+              <NextMarker>2!80!bGFyZ2UuYmlu</NextMarker>
+            </EnumerationResults>"#;
+
+        let page = parse_blob_list(body).unwrap();
+
+        assert_eq!(
+            page.blobs[0],
+            BlobInfo {
+                name: String::from("folder/my file.txt"),
+                etag: String::from("0x8D612B5F2A8C2B1"),
+                last_modified: Utc.ymd(2018, 9, 4).and_hms(10, 0, 0),
+                content_length: 5,
+                content_md5: Some(String::from("XUFAKrxLKna5cZ2REBfFkg==")),
+            }
+        );
+        assert_eq!(page.blobs[1].content_md5, None);
+        assert_eq!(page.next_marker, Some(String::from("2!80!bGFyZ2UuYmlu")));
+    }
+
+    #[test]
+    fn test_last_blob_list_page_has_no_marker() {
+        let body = br#"<EnumerationResults><Blobs /><NextMarker /></EnumerationResults>"#;
+
+        let page = parse_blob_list(body).unwrap();
+
+        assert!(page.blobs.is_empty());
+        assert_eq!(page.next_marker, None);
     }
 }
@@ -0,0 +1,560 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const STATE_FOLDER: &str = ".bucket";
+const STATE_FILE: &str = "state.log";
+const QUEUE_FILE: &str = "queue.log";
+
+#[derive(Debug, Fail)]
+pub enum SyncStateError {
+    #[fail(display = "An io error has occurred - {:?}", _0)]
+    IOError(io::Error),
+    #[fail(display = "Unable to serialize sync state - {:?}", _0)]
+    SerializationError(serde_json::Error),
+}
+
+impl From<io::Error> for SyncStateError {
+    fn from(error: io::Error) -> Self {
+        SyncStateError::IOError(error)
+    }
+}
+
+impl From<serde_json::Error> for SyncStateError {
+    fn from(error: serde_json::Error) -> Self {
+        SyncStateError::SerializationError(error)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncEntry {
+    pub local_modified: i64,
+    pub local_size: u64,
+    pub hash: String,
+    pub etag: String,
+}
+
+// A storage operation that could not be carried out, most likely because the
+// network is down. Operations on the same blob are replayed in the order they
+// happened, while ones on unrelated blobs don't have to wait for each other.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Operation {
+    Upload(String),
+    Delete(String),
+    Rename(String, String),
+}
+
+impl Operation {
+    fn blob_names(&self) -> Vec<&str> {
+        match self {
+            Operation::Upload(name) | Operation::Delete(name) => vec![name],
+            Operation::Rename(from, to) => vec![from, to],
+        }
+    }
+
+    fn involves(&self, blob_name: &str) -> bool {
+        self.blob_names().contains(&blob_name)
+    }
+
+    // a folder overlaps everything inside it, since deleting or renaming it
+    // has to be ordered with changes to its files
+    pub fn overlaps(&self, other: &Operation) -> bool {
+        self.blob_names().iter().any(|a| {
+            other.blob_names().iter().any(|b| {
+                a == b || a.starts_with(&format!("{}/", b)) || b.starts_with(&format!("{}/", a))
+            })
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum Record {
+    Set(String, SyncEntry),
+    Remove(String),
+}
+
+// Each queued operation is numbered, so a reload removes exactly the entry
+// that was removed before, even when the same operation is queued twice.
+#[derive(Serialize, Deserialize)]
+enum QueueRecord {
+    Push(u64, Operation),
+    Remove(u64),
+}
+
+struct Inner {
+    entries: HashMap<String, SyncEntry>,
+    log: Option<File>,
+    queue: VecDeque<(u64, Operation)>,
+    queue_log: Option<File>,
+    next_id: u64,
+}
+
+pub struct SyncState {
+    inner: Mutex<Inner>,
+}
+
+impl SyncState {
+    pub fn new() -> SyncState {
+        SyncState {
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                log: None,
+                queue: VecDeque::new(),
+                queue_log: None,
+                next_id: 0,
+            }),
+        }
+    }
+
+    pub fn load(root_folder: &str) -> Result<SyncState, SyncStateError> {
+        let folder = Path::new(root_folder).join(STATE_FOLDER);
+        fs::create_dir_all(&folder)?;
+        let path = folder.join(STATE_FILE);
+
+        let mut entries = HashMap::new();
+        for record in read_records(&path)? {
+            match record {
+                Record::Set(name, entry) => {
+                    entries.insert(name, entry);
+                }
+                Record::Remove(name) => {
+                    entries.remove(&name);
+                }
+            }
+        }
+        compact(
+            &path,
+            entries
+                .iter()
+                .map(|(name, entry)| Record::Set(name.clone(), entry.clone())),
+        )?;
+        let log = OpenOptions::new().append(true).open(&path)?;
+
+        let queue_path = folder.join(QUEUE_FILE);
+        let mut queue = VecDeque::new();
+        let mut next_id = 0;
+        for record in read_records(&queue_path)? {
+            match record {
+                QueueRecord::Push(id, operation) => {
+                    next_id = next_id.max(id + 1);
+                    queue.push_back((id, operation));
+                }
+                QueueRecord::Remove(id) => queue.retain(|&(queued_id, _)| queued_id != id),
+            }
+        }
+        compact(
+            &queue_path,
+            queue
+                .iter()
+                .map(|&(id, ref operation)| QueueRecord::Push(id, operation.clone())),
+        )?;
+        let queue_log = OpenOptions::new().append(true).open(&queue_path)?;
+
+        Ok(SyncState {
+            inner: Mutex::new(Inner {
+                entries,
+                log: Some(log),
+                queue,
+                queue_log: Some(queue_log),
+                next_id,
+            }),
+        })
+    }
+
+    pub fn get(&self, blob_name: &str) -> Option<SyncEntry> {
+        self.inner.lock().unwrap().entries.get(blob_name).cloned()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.inner.lock().unwrap().entries.keys().cloned().collect()
+    }
+
+    pub fn set(&self, blob_name: &str, entry: SyncEntry) {
+        let mut inner = self.inner.lock().unwrap();
+        append(
+            &mut inner.log,
+            &Record::Set(String::from(blob_name), entry.clone()),
+        );
+        inner.entries.insert(String::from(blob_name), entry);
+    }
+
+    pub fn remove(&self, blob_name: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries.remove(blob_name).is_some() {
+            append(&mut inner.log, &Record::Remove(String::from(blob_name)));
+        }
+    }
+
+    // the queue is only ever appended to, so queueing stays cheap however
+    // long it grows while storage is unavailable
+    pub fn enqueue(&self, operation: Operation) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(replaced) = coalesce(&mut inner.queue, &operation) {
+            append(&mut inner.queue_log, &QueueRecord::Remove(replaced));
+        }
+        let id = inner.next_id;
+        inner.next_id += 1;
+        append(
+            &mut inner.queue_log,
+            &QueueRecord::Push(id, operation.clone()),
+        );
+        inner.queue.push_back((id, operation));
+    }
+
+    pub fn has_pending_operations(&self) -> bool {
+        !self.inner.lock().unwrap().queue.is_empty()
+    }
+
+    pub fn has_pending_operations_on(&self, operation: &Operation) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .queue
+            .iter()
+            .any(|&(_, ref o)| o.overlaps(operation))
+    }
+
+    pub fn next_operation(&self) -> Option<Operation> {
+        let inner = self.inner.lock().unwrap();
+        inner.queue.front().map(|&(_, ref o)| o.clone())
+    }
+
+    pub fn pending_operations(&self) -> Vec<Operation> {
+        let inner = self.inner.lock().unwrap();
+        inner.queue.iter().map(|&(_, ref o)| o.clone()).collect()
+    }
+
+    pub fn complete_operation(&self, operation: &Operation) {
+        let mut inner = self.inner.lock().unwrap();
+        // it may already have been coalesced away while it was being replayed
+        if let Some(i) = inner.queue.iter().position(|&(_, ref o)| o == operation) {
+            let (id, _) = inner.queue.remove(i).unwrap();
+            append(&mut inner.queue_log, &QueueRecord::Remove(id));
+        }
+    }
+}
+
+// Only the latest operation on a blob can be replaced by a new upload or
+// delete of it. Anything before a rename involving the blob has to stay.
+fn coalesce(queue: &mut VecDeque<(u64, Operation)>, operation: &Operation) -> Option<u64> {
+    let blob_name = match operation {
+        Operation::Upload(name) | Operation::Delete(name) => name,
+        Operation::Rename(_, _) => return None,
+    };
+    let i = queue
+        .iter()
+        .rposition(|&(_, ref o)| o.involves(blob_name))?;
+    match queue[i].1 {
+        Operation::Upload(_) | Operation::Delete(_) => queue.remove(i).map(|(id, _)| id),
+        Operation::Rename(_, _) => None,
+    }
+}
+
+pub fn hash(data: &[u8]) -> String {
+    base64::encode(&md5::compute(data)[..])
+}
+
+pub fn hash_reader<R: Read>(reader: R) -> Result<String, io::Error> {
+    let mut reader = HashingReader::new(reader);
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.hash())
+}
+
+// Hashes content as it is read, so large files never need to be held in memory.
+pub struct HashingReader<R> {
+    inner: R,
+    context: md5::Context,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> HashingReader<R> {
+        HashingReader {
+            inner,
+            context: md5::Context::new(),
+        }
+    }
+
+    pub fn hash(self) -> String {
+        base64::encode(&self.context.compute()[..])
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.context.consume(&buf[..read]);
+        Ok(read)
+    }
+}
+
+// only the folder at the top of the root folder holds bucket's own state, a
+// .bucket folder anywhere below it is synced like any other
+pub fn is_state_path(root_folder: &str, p: &Path) -> bool {
+    p.starts_with(Path::new(root_folder).join(STATE_FOLDER))
+}
+
+// a torn final line from a crash mid-write is skipped
+fn read_records<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>, SyncStateError> {
+    let mut records = Vec::new();
+    if path.exists() {
+        for line in BufReader::new(File::open(path)?).lines() {
+            match serde_json::from_str(&line?) {
+                Ok(record) => records.push(record),
+                Err(e) => trace!("Skipping sync state record - {}", e),
+            }
+        }
+    }
+    Ok(records)
+}
+
+fn append<T: Serialize>(log: &mut Option<File>, record: &T) {
+    if let Some(ref mut log) = *log {
+        let result = serde_json::to_string(record)
+            .map_err(SyncStateError::from)
+            .and_then(|line| writeln!(log, "{}", line).map_err(SyncStateError::from));
+        if let Err(e) = result {
+            trace!("Error writing sync state - {}", e);
+        }
+    }
+}
+
+fn compact<T, I>(path: &PathBuf, records: I) -> Result<(), SyncStateError>
+where
+    T: Serialize,
+    I: Iterator<Item = T>,
+{
+    let temp_path = path.with_extension("tmp");
+    {
+        let mut file = File::create(&temp_path)?;
+        for record in records {
+            writeln!(file, "{}", serde_json::to_string(&record)?)?;
+        }
+        file.sync_all()?;
+    }
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn test_folder(name: &str) -> String {
+        let folder = env::temp_dir().join(format!(
+            "bucket-sync-state-{}-{}",
+            name,
+            ::std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&folder);
+        String::from(folder.to_str().unwrap())
+    }
+
+    fn entry(etag: &str) -> SyncEntry {
+        SyncEntry {
+            local_modified: 1,
+            local_size: 2,
+            hash: String::from("hash"),
+            etag: String::from(etag),
+        }
+    }
+
+    #[test]
+    fn test_entries_survive_reload() {
+        let folder = test_folder("reload");
+        {
+            let state = SyncState::load(&folder).unwrap();
+            state.set("a.txt", entry("1"));
+            state.set("b.txt", entry("1"));
+            state.set("a.txt", entry("2"));
+            state.remove("b.txt");
+        }
+
+        let state = SyncState::load(&folder).unwrap();
+
+        assert_eq!(state.get("a.txt"), Some(entry("2")));
+        assert_eq!(state.get("b.txt"), None);
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_torn_record_is_skipped() {
+        let folder = test_folder("torn");
+        {
+            let state = SyncState::load(&folder).unwrap();
+            state.set("a.txt", entry("1"));
+        }
+        let path = Path::new(&folder).join(STATE_FOLDER).join(STATE_FILE);
+        let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(log, "{{\"Set\":[\"b.txt\"").unwrap();
+
+        let state = SyncState::load(&folder).unwrap();
+
+        assert_eq!(state.get("a.txt"), Some(entry("1")));
+        assert_eq!(state.get("b.txt"), None);
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_queued_operations_survive_reload() {
+        let folder = test_folder("queue");
+        {
+            let state = SyncState::load(&folder).unwrap();
+            state.enqueue(Operation::Upload(String::from("a.txt")));
+            state.enqueue(Operation::Rename(
+                String::from("b.txt"),
+                String::from("c.txt"),
+            ));
+        }
+
+        let state = SyncState::load(&folder).unwrap();
+        assert_eq!(
+            state.next_operation(),
+            Some(Operation::Upload(String::from("a.txt")))
+        );
+        state.complete_operation(&Operation::Upload(String::from("a.txt")));
+        assert_eq!(
+            state.next_operation(),
+            Some(Operation::Rename(
+                String::from("b.txt"),
+                String::from("c.txt")
+            ))
+        );
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_completed_operations_stay_removed_after_reload() {
+        let folder = test_folder("queue-removed");
+        {
+            let state = SyncState::load(&folder).unwrap();
+            state.enqueue(Operation::Upload(String::from("a.txt")));
+            state.enqueue(Operation::Upload(String::from("b.txt")));
+            state.enqueue(Operation::Delete(String::from("a.txt")));
+            state.complete_operation(&Operation::Upload(String::from("b.txt")));
+        }
+
+        let state = SyncState::load(&folder).unwrap();
+
+        assert_eq!(
+            state.pending_operations(),
+            vec![Operation::Delete(String::from("a.txt"))]
+        );
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_coalesced_operation_stays_removed_after_reload() {
+        let folder = test_folder("queue-coalesced");
+        let expected = vec![
+            Operation::Upload(String::from("a.txt")),
+            Operation::Rename(String::from("a.txt"), String::from("b.txt")),
+            Operation::Delete(String::from("a.txt")),
+        ];
+        {
+            let state = SyncState::load(&folder).unwrap();
+            state.enqueue(Operation::Upload(String::from("a.txt")));
+            state.enqueue(Operation::Rename(
+                String::from("a.txt"),
+                String::from("b.txt"),
+            ));
+            state.enqueue(Operation::Upload(String::from("a.txt")));
+            state.enqueue(Operation::Delete(String::from("a.txt")));
+            assert_eq!(state.pending_operations(), expected);
+        }
+
+        let state = SyncState::load(&folder).unwrap();
+
+        assert_eq!(state.pending_operations(), expected);
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_operations_overlap_on_shared_blobs_and_folders() {
+        let upload = Operation::Upload(String::from("folder/a.txt"));
+
+        assert!(upload.overlaps(&Operation::Delete(String::from("folder"))));
+        assert!(upload.overlaps(&Operation::Rename(
+            String::from("b.txt"),
+            String::from("folder/a.txt")
+        )));
+        assert!(!upload.overlaps(&Operation::Delete(String::from("folder/b.txt"))));
+        assert!(!upload.overlaps(&Operation::Delete(String::from("fold"))));
+    }
+
+    #[test]
+    fn test_delete_replaces_queued_upload() {
+        let state = SyncState::new();
+        state.enqueue(Operation::Upload(String::from("a.txt")));
+        state.enqueue(Operation::Upload(String::from("b.txt")));
+        state.enqueue(Operation::Delete(String::from("a.txt")));
+
+        assert_eq!(
+            state.next_operation(),
+            Some(Operation::Upload(String::from("b.txt")))
+        );
+        state.complete_operation(&Operation::Upload(String::from("b.txt")));
+        assert_eq!(
+            state.next_operation(),
+            Some(Operation::Delete(String::from("a.txt")))
+        );
+    }
+
+    #[test]
+    fn test_repeated_uploads_are_queued_once() {
+        let state = SyncState::new();
+        state.enqueue(Operation::Upload(String::from("a.txt")));
+        state.enqueue(Operation::Upload(String::from("a.txt")));
+
+        state.complete_operation(&Operation::Upload(String::from("a.txt")));
+        assert!(!state.has_pending_operations());
+    }
+
+    #[test]
+    fn test_operations_before_a_rename_are_kept() {
+        let state = SyncState::new();
+        state.enqueue(Operation::Upload(String::from("a.txt")));
+        state.enqueue(Operation::Rename(
+            String::from("a.txt"),
+            String::from("b.txt"),
+        ));
+        state.enqueue(Operation::Delete(String::from("a.txt")));
+
+        assert_eq!(
+            state.next_operation(),
+            Some(Operation::Upload(String::from("a.txt")))
+        );
+    }
+
+    #[test]
+    fn test_hash_reader_matches_hash() {
+        let data = vec![7u8; 100_000];
+
+        assert_eq!(hash_reader(&data[..]).unwrap(), hash(&data));
+    }
+
+    #[test]
+    fn test_state_paths_are_detected() {
+        assert!(is_state_path(
+            "/bucket",
+            Path::new("/bucket/.bucket/state.log")
+        ));
+        assert!(!is_state_path(
+            "/bucket",
+            Path::new("/bucket/folder/file.txt")
+        ));
+        assert!(!is_state_path(
+            "/bucket",
+            Path::new("/bucket/folder/.bucket/file.txt")
+        ));
+        assert!(!is_state_path(
+            "/home/.bucket/bucket",
+            Path::new("/home/.bucket/bucket/file.txt")
+        ));
+    }
+}
@@ -0,0 +1,462 @@
+use super::file_system::{self, FileContent, FileInfo, FileSystem, FileSystemError};
+use super::storage::{BlobData, BlobInfo, BlobPage, Storage, StorageError};
+use chrono::{DateTime, Utc};
+use hyper::StatusCode;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use url::percent_encoding::{percent_decode, utf8_percent_encode, DEFAULT_ENCODE_SET};
+
+#[derive(Debug, Clone, PartialEq)]
+struct MemoryBlob {
+    data: Vec<u8>,
+    etag: String,
+    last_modified: DateTime<Utc>,
+}
+
+// An in-memory Storage that keeps what is written to it, so tests can check
+// what ends up stored rather than which methods were called. Blobs are kept
+// under their decoded names, which is how Azure stores and lists them.
+pub struct MemoryStorage {
+    blobs: RefCell<BTreeMap<String, MemoryBlob>>,
+    next_etag: Cell<u64>,
+    page_size: usize,
+    failures: RefCell<BTreeMap<String, (u32, StatusCode)>>,
+    offline: Cell<bool>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::with_page_size(1000)
+    }
+
+    // small pages make listings follow continuation markers
+    pub fn with_page_size(page_size: usize) -> MemoryStorage {
+        MemoryStorage {
+            blobs: RefCell::new(BTreeMap::new()),
+            next_etag: Cell::new(1),
+            page_size: page_size.max(1),
+            failures: RefCell::new(BTreeMap::new()),
+            offline: Cell::new(false),
+        }
+    }
+
+    pub fn put(&self, blob_name: &str, data: &[u8]) -> String {
+        self.put_modified(blob_name, data, Utc::now())
+    }
+
+    pub fn put_modified(
+        &self,
+        blob_name: &str,
+        data: &[u8],
+        last_modified: DateTime<Utc>,
+    ) -> String {
+        let etag = format!("etag-{}", self.next_etag.get());
+        self.next_etag.set(self.next_etag.get() + 1);
+        self.blobs.borrow_mut().insert(
+            decode(blob_name),
+            MemoryBlob {
+                data: data.to_vec(),
+                etag: etag.clone(),
+                last_modified,
+            },
+        );
+        etag
+    }
+
+    // The next `times` requests for the blob, or listings of the prefix, fail
+    // the way they do while the service is busy.
+    pub fn fail(&self, name: &str, times: u32) {
+        self.failures
+            .borrow_mut()
+            .insert(decode(name), (times, StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    // like fail, but with an error that isn't worth retrying
+    pub fn refuse(&self, name: &str, times: u32) {
+        self.failures
+            .borrow_mut()
+            .insert(decode(name), (times, StatusCode::FORBIDDEN));
+    }
+
+    // while offline every request fails and nothing is changed
+    pub fn set_offline(&self, offline: bool) {
+        self.offline.set(offline);
+    }
+
+    fn check_available(&self, name: &str) -> Result<(), StorageError> {
+        if self.offline.get() {
+            return Err(StorageError::UnexpectedStatus(
+                StatusCode::SERVICE_UNAVAILABLE,
+                None,
+            ));
+        }
+        match self.failures.borrow_mut().get_mut(&decode(name)) {
+            Some(&mut (ref mut times, status)) if *times > 0 => {
+                *times -= 1;
+                Err(StorageError::UnexpectedStatus(status, None))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn contents(&self, blob_name: &str) -> Option<Vec<u8>> {
+        self.blobs
+            .borrow()
+            .get(&decode(blob_name))
+            .map(|b| b.data.clone())
+    }
+
+    pub fn etag(&self, blob_name: &str) -> Option<String> {
+        self.blobs
+            .borrow()
+            .get(&decode(blob_name))
+            .map(|b| b.etag.clone())
+    }
+
+    pub fn blob_names(&self) -> Vec<String> {
+        self.blobs.borrow().keys().cloned().collect()
+    }
+
+    fn put_content(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        len: u64,
+    ) -> Result<String, StorageError> {
+        let mut data = Vec::new();
+        content.take(len).read_to_end(&mut data)?;
+        Ok(self.put(blob_name, &data))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn upload(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        len: u64,
+    ) -> Result<String, StorageError> {
+        self.check_available(blob_name)?;
+        self.put_content(blob_name, content, len)
+    }
+
+    fn upload_if_match(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        len: u64,
+        etag: &str,
+    ) -> Result<String, StorageError> {
+        self.check_available(blob_name)?;
+        if self.etag(blob_name).as_ref().map(|e| e.as_str()) != Some(etag) {
+            return Err(StorageError::ConditionNotMet);
+        }
+        self.put_content(blob_name, content, len)
+    }
+
+    fn upload_if_absent(
+        &self,
+        blob_name: &str,
+        content: &mut FileContent,
+        len: u64,
+    ) -> Result<String, StorageError> {
+        self.check_available(blob_name)?;
+        if self.etag(blob_name).is_some() {
+            return Err(StorageError::ConditionNotMet);
+        }
+        self.put_content(blob_name, content, len)
+    }
+
+    fn download(&self, blob_name: &str) -> Result<BlobData, StorageError> {
+        self.check_available(blob_name)?;
+        match self.blobs.borrow().get(&decode(blob_name)) {
+            Some(blob) => Ok(BlobData {
+                content: Box::new(io::Cursor::new(blob.data.clone())),
+                etag: blob.etag.clone(),
+            }),
+            None => Err(StorageError::PathNotFound),
+        }
+    }
+
+    fn delete(&self, blob_name: &str) -> Result<(), StorageError> {
+        self.check_available(blob_name)?;
+        match self.blobs.borrow_mut().remove(&decode(blob_name)) {
+            Some(_) => Ok(()),
+            None => Err(StorageError::PathNotFound),
+        }
+    }
+
+    fn copy(&self, from_blob_name: &str, to_blob_name: &str) -> Result<String, StorageError> {
+        self.check_available(from_blob_name)?;
+        match self.contents(from_blob_name) {
+            Some(data) => Ok(self.put(to_blob_name, &data)),
+            None => Err(StorageError::PathNotFound),
+        }
+    }
+
+    fn list_blobs_page(
+        &self,
+        prefix: &str,
+        marker: Option<&str>,
+    ) -> Result<BlobPage, StorageError> {
+        self.check_available(prefix)?;
+        let prefix = decode(prefix);
+        let mut blobs: Vec<BlobInfo> = self
+            .blobs
+            .borrow()
+            .iter()
+            .filter(|&(name, _)| name.starts_with(&prefix))
+            .filter(|&(name, _)| marker.map(|m| name.as_str() > m).unwrap_or(true))
+            .take(self.page_size + 1)
+            .map(|(name, blob)| BlobInfo {
+                name: name.clone(),
+                etag: blob.etag.clone(),
+                last_modified: blob.last_modified,
+                content_length: blob.data.len() as u64,
+                content_md5: Some(::sync_state::hash(&blob.data)),
+            })
+            .collect();
+
+        // the marker is the last name on the page, when there is another page
+        let next_marker = if blobs.len() > self.page_size {
+            blobs.truncate(self.page_size);
+            blobs.last().map(|b| b.name.clone())
+        } else {
+            None
+        };
+
+        Ok(BlobPage { blobs, next_marker })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct MemoryFile {
+    data: Vec<u8>,
+    last_modified: DateTime<Utc>,
+}
+
+// Files are kept under paths in a root folder that doesn't exist on disk.
+pub struct MemoryFileSystem {
+    root_folder: PathBuf,
+    files: RefCell<BTreeMap<PathBuf, MemoryFile>>,
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> MemoryFileSystem {
+        MemoryFileSystem {
+            root_folder: PathBuf::from("/bucket-memory"),
+            files: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    // the full path of a file given relative to the root folder
+    pub fn path(&self, name: &str) -> PathBuf {
+        let mut path = self.root_folder.clone();
+        for part in name.split('/').filter(|p| !p.is_empty()) {
+            path.push(part);
+        }
+        path
+    }
+
+    pub fn put(&self, name: &str, data: &[u8]) -> PathBuf {
+        self.put_modified(name, data, Utc::now())
+    }
+
+    pub fn put_modified(&self, name: &str, data: &[u8], last_modified: DateTime<Utc>) -> PathBuf {
+        let path = self.path(name);
+        self.files.borrow_mut().insert(
+            path.clone(),
+            MemoryFile {
+                data: data.to_vec(),
+                last_modified,
+            },
+        );
+        path
+    }
+
+    pub fn contents(&self, p: &Path) -> Option<Vec<u8>> {
+        self.files.borrow().get(p).map(|f| f.data.clone())
+    }
+
+    fn not_found(p: &Path) -> FileSystemError {
+        FileSystemError::IOError(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{:?} does not exist", p),
+        ))
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn list_files(&self) -> Vec<PathBuf> {
+        self.files.borrow().keys().cloned().collect()
+    }
+
+    fn get_file_info(&self, p: &PathBuf) -> Result<FileInfo, FileSystemError> {
+        match self.files.borrow().get(p) {
+            Some(file) => Ok(FileInfo {
+                size: file.data.len() as u64,
+                last_modified: file.last_modified,
+            }),
+            None => Err(MemoryFileSystem::not_found(p)),
+        }
+    }
+
+    fn get_blob_name(&self, p: &PathBuf) -> Result<String, FileSystemError> {
+        let stripped = p
+            .strip_prefix(&self.root_folder)
+            .map_err(|_| FileSystemError::OutsideRootFolder(p.clone()))?;
+        match stripped.to_str() {
+            Some(name) => Ok(self.encode_file_name(name)),
+            None => Err(FileSystemError::InvalidFileName(p.clone())),
+        }
+    }
+
+    fn get_file_path(&self, blob_name: &str) -> Result<PathBuf, FileSystemError> {
+        file_system::blob_path(&self.root_folder, blob_name)
+    }
+
+    fn open_file(&self, p: &PathBuf) -> Result<Box<FileContent>, FileSystemError> {
+        match self.contents(p) {
+            Some(data) => Ok(Box::new(io::Cursor::new(data))),
+            None => Err(MemoryFileSystem::not_found(p)),
+        }
+    }
+
+    fn write_file(&self, p: &PathBuf, content: &mut Read) -> Result<(), FileSystemError> {
+        let mut data = Vec::new();
+        content.read_to_end(&mut data)?;
+        self.files.borrow_mut().insert(
+            p.clone(),
+            MemoryFile {
+                data,
+                last_modified: Utc::now(),
+            },
+        );
+        Ok(())
+    }
+
+    fn remove_file(&self, p: &PathBuf) -> Result<(), FileSystemError> {
+        match self.files.borrow_mut().remove(p) {
+            Some(_) => Ok(()),
+            None => Err(MemoryFileSystem::not_found(p)),
+        }
+    }
+
+    // folders only exist while there are files in them
+    fn is_dir(&self, p: &PathBuf) -> bool {
+        self.files
+            .borrow()
+            .keys()
+            .any(|f| f != p && f.starts_with(p))
+    }
+
+    fn is_file(&self, p: &PathBuf) -> bool {
+        self.files.borrow().contains_key(p)
+    }
+
+    fn encode_file_name(&self, f: &str) -> String {
+        let normalized = f.replace("\\", "/");
+        utf8_percent_encode(&normalized, DEFAULT_ENCODE_SET).collect()
+    }
+}
+
+fn decode(blob_name: &str) -> String {
+    percent_decode(blob_name.as_bytes())
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storage_keeps_uploaded_content() {
+        let storage = MemoryStorage::new();
+
+        let etag = storage
+            .upload("my%20file.txt", &mut io::Cursor::new(b"hello".to_vec()), 5)
+            .unwrap();
+
+        assert_eq!(storage.contents("my file.txt"), Some(b"hello".to_vec()));
+        assert_eq!(storage.etag("my%20file.txt"), Some(etag));
+        assert_eq!(storage.blob_names(), vec!["my file.txt"]);
+    }
+
+    #[test]
+    fn test_storage_rejects_upload_with_old_etag() {
+        let storage = MemoryStorage::new();
+        let old_etag = storage.put("file.txt", b"one");
+        storage.put("file.txt", b"two");
+
+        let result = storage.upload_if_match(
+            "file.txt",
+            &mut io::Cursor::new(b"three".to_vec()),
+            5,
+            &old_etag,
+        );
+
+        match result {
+            Err(StorageError::ConditionNotMet) => (),
+            _ => panic!("expected ConditionNotMet"),
+        }
+        assert_eq!(storage.contents("file.txt"), Some(b"two".to_vec()));
+    }
+
+    #[test]
+    fn test_storage_fails_requests_it_is_told_to() {
+        let storage = MemoryStorage::new();
+        storage.put("file.txt", b"hello");
+        storage.fail("file.txt", 1);
+
+        assert!(storage.delete("file.txt").unwrap_err().is_retryable());
+        assert!(storage.delete("file.txt").is_ok());
+
+        storage.set_offline(true);
+        assert!(storage.list_blobs().unwrap_err().is_retryable());
+    }
+
+    #[test]
+    fn test_storage_lists_across_pages() {
+        let storage = MemoryStorage::with_page_size(2);
+        for name in &["a/1", "a/2", "a/3", "b/1", "c"] {
+            storage.put(name, b"");
+        }
+
+        assert_eq!(storage.list_blobs().unwrap().len(), 5);
+        assert_eq!(
+            storage.list_folder_blobs("a").unwrap(),
+            vec!["a/1", "a/2", "a/3"]
+        );
+        assert_eq!(
+            storage.list_blobs_page("", None).unwrap().next_marker,
+            Some(String::from("a/2"))
+        );
+    }
+
+    #[test]
+    fn test_file_system_maps_paths_to_blob_names() {
+        let file_system = MemoryFileSystem::new();
+        let path = file_system.put("folder/my file.txt", b"hello");
+
+        let blob_name = file_system.get_blob_name(&path).unwrap();
+
+        assert_eq!(blob_name, "folder/my%20file.txt");
+        assert_eq!(file_system.get_file_path(&blob_name).unwrap(), path);
+        assert_eq!(file_system.get_file_info(&path).unwrap().size, 5);
+        assert_eq!(file_system.list_files(), vec![path]);
+    }
+
+    #[test]
+    fn test_file_system_removes_files() {
+        let file_system = MemoryFileSystem::new();
+        let path = file_system.put("file.txt", b"hello");
+
+        file_system.remove_file(&path).unwrap();
+
+        assert_eq!(file_system.contents(&path), None);
+        assert!(file_system.remove_file(&path).is_err());
+    }
+}
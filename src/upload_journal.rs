@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+// Records which blocks of an in-progress block upload have already been sent,
+// so an interrupted upload can carry on from where it stopped. Azure keeps
+// uncommitted blocks for a week, after which the upload starts again.
+pub struct UploadJournal {
+    folder: PathBuf,
+}
+
+impl UploadJournal {
+    pub fn new(folder: PathBuf) -> UploadJournal {
+        UploadJournal { folder }
+    }
+
+    pub fn uploaded_blocks(&self, blob_name: &str, block_size: usize) -> HashMap<usize, String> {
+        let mut blocks = HashMap::new();
+        let file = match File::open(self.journal_path(blob_name)) {
+            Ok(file) => file,
+            Err(_) => return blocks,
+        };
+
+        let mut lines = BufReader::new(file).lines().filter_map(|l| l.ok());
+        if lines.next() != Some(format!("{} {}", blob_name, block_size)) {
+            // different block layout, nothing can be reused
+            return blocks;
+        }
+        for line in lines {
+            let mut parts = line.splitn(2, ' ');
+            if let (Some(index), Some(md5)) = (parts.next(), parts.next()) {
+                if let Ok(index) = index.parse() {
+                    blocks.insert(index, String::from(md5));
+                }
+            }
+        }
+        blocks
+    }
+
+    pub fn start(&self, blob_name: &str, block_size: usize) {
+        let result = fs::create_dir_all(&self.folder)
+            .and_then(|_| File::create(self.journal_path(blob_name)))
+            .and_then(|mut file| writeln!(file, "{} {}", blob_name, block_size));
+        if let Err(e) = result {
+            trace!("Error starting upload journal for {} - {}", blob_name, e);
+        }
+    }
+
+    pub fn record_block(&self, blob_name: &str, index: usize, md5: &str) {
+        let result = OpenOptions::new()
+            .append(true)
+            .open(self.journal_path(blob_name))
+            .and_then(|mut file| writeln!(file, "{} {}", index, md5));
+        if let Err(e) = result {
+            trace!("Error recording block {} of {} - {}", index, blob_name, e);
+        }
+    }
+
+    pub fn finish(&self, blob_name: &str) {
+        let _ = fs::remove_file(self.journal_path(blob_name));
+    }
+
+    fn journal_path(&self, blob_name: &str) -> PathBuf {
+        self.folder.join(format!("{:x}", md5::compute(blob_name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn test_journal(name: &str) -> UploadJournal {
+        let folder = env::temp_dir().join(format!(
+            "bucket-upload-journal-{}-{}",
+            name,
+            ::std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&folder);
+        UploadJournal::new(folder)
+    }
+
+    #[test]
+    fn test_recorded_blocks_are_returned() {
+        let journal = test_journal("recorded");
+        journal.start("video.mp4", 4);
+        journal.record_block("video.mp4", 0, "md5-0");
+        journal.record_block("video.mp4", 1, "md5-1");
+
+        let blocks = journal.uploaded_blocks("video.mp4", 4);
+
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[&1], "md5-1");
+        fs::remove_dir_all(&journal.folder).unwrap();
+    }
+
+    #[test]
+    fn test_blocks_of_a_different_size_are_not_reused() {
+        let journal = test_journal("size");
+        journal.start("video.mp4", 4);
+        journal.record_block("video.mp4", 0, "md5-0");
+
+        assert!(journal.uploaded_blocks("video.mp4", 8).is_empty());
+        fs::remove_dir_all(&journal.folder).unwrap();
+    }
+
+    #[test]
+    fn test_finished_upload_has_no_blocks() {
+        let journal = test_journal("finished");
+        journal.start("video.mp4", 4);
+        journal.record_block("video.mp4", 0, "md5-0");
+
+        journal.finish("video.mp4");
+
+        assert!(journal.uploaded_blocks("video.mp4", 4).is_empty());
+        fs::remove_dir_all(&journal.folder).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_upload_has_no_blocks() {
+        let journal = test_journal("unknown");
+
+        assert!(journal.uploaded_blocks("video.mp4", 4).is_empty());
+    }
+}
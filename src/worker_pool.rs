@@ -0,0 +1,254 @@
+use super::event_handlers::EventDispatcher;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+// how many jobs can wait for each worker before dispatching blocks
+const QUEUE_DEPTH: usize = 64;
+
+pub enum Job {
+    Call(String, PathBuf),
+    Rename(String, PathBuf, PathBuf),
+    Flush(Sender<()>),
+}
+
+// Runs events on a fixed number of worker threads. Every event for a path goes
+// to the same worker, so events for one file are always handled in order.
+#[derive(Clone)]
+pub struct WorkerPool {
+    workers: Vec<SyncSender<Job>>,
+    exclusive: Arc<Fn(&str, &PathBuf) -> bool + Send + Sync>,
+    // held for writing while work runs outside the pool, to pause dispatch
+    paused: Arc<RwLock<()>>,
+}
+
+impl WorkerPool {
+    pub fn new<E, W>(size: usize, exclusive: E, start_worker: W) -> WorkerPool
+    where
+        E: Fn(&str, &PathBuf) -> bool + Send + Sync + 'static,
+        W: Fn(Receiver<Job>) + Send + Sync + 'static,
+    {
+        let start_worker = Arc::new(start_worker);
+        let workers = (0..size.max(1))
+            .map(|_| {
+                let (tx, rx) = sync_channel(QUEUE_DEPTH);
+                let start_worker = start_worker.clone();
+                thread::spawn(move || start_worker(rx));
+                tx
+            })
+            .collect();
+
+        WorkerPool {
+            workers,
+            exclusive: Arc::new(exclusive),
+            paused: Arc::new(RwLock::new(())),
+        }
+    }
+
+    // a worker that has stopped can't reply, so only the flushes that were
+    // sent are waited for, and a flush it dropped ends the wait early
+    pub fn wait_idle(&self) {
+        let (tx, rx) = channel();
+        let sent = self
+            .workers
+            .iter()
+            .filter(|worker| worker.send(Job::Flush(tx.clone())).is_ok())
+            .count();
+        drop(tx);
+        for _ in 0..sent {
+            if rx.recv().is_err() {
+                trace!("Worker stopped before it was idle");
+                break;
+            }
+        }
+    }
+
+    // Runs f once everything dispatched so far has been handled, and holds
+    // back anything dispatched from other threads until it returns.
+    pub fn run_exclusive<F: FnOnce()>(&self, f: F) {
+        let _paused = self.paused.write().unwrap();
+        self.wait_idle();
+        f();
+    }
+
+    fn send(&self, key: &PathBuf, job: Job) {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let worker = &self.workers[hasher.finish() as usize % self.workers.len()];
+        if let Err(e) = worker.send(job) {
+            trace!("Worker has stopped - {}", e);
+        }
+    }
+
+    // events that can touch any number of paths wait for everything before
+    // them, and everything after them waits for them
+    fn send_exclusive(&self, key: &PathBuf, job: Job) {
+        self.wait_idle();
+        self.send(key, job);
+        self.wait_idle();
+    }
+}
+
+impl EventDispatcher for WorkerPool {
+    fn call(&self, event_name: &str, path: &PathBuf) {
+        let _dispatching = self.paused.read().unwrap();
+        let job = Job::Call(String::from(event_name), path.clone());
+        if (self.exclusive)(event_name, path) {
+            self.send_exclusive(path, job);
+        } else {
+            self.send(path, job);
+        }
+    }
+
+    fn call_rename(&self, event_name: &str, from: &PathBuf, to: &PathBuf) {
+        let _dispatching = self.paused.read().unwrap();
+        let job = Job::Rename(String::from(event_name), from.clone(), to.clone());
+        self.send_exclusive(from, job);
+    }
+}
+
+pub fn run_jobs(jobs: Receiver<Job>, evts: &EventDispatcher) {
+    for job in jobs {
+        match job {
+            Job::Call(event_name, path) => evts.call(&event_name, &path),
+            Job::Rename(event_name, from, to) => evts.call_rename(&event_name, &from, &to),
+            Job::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    struct RecordingDispatcher {
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl EventDispatcher for RecordingDispatcher {
+        fn call(&self, event_name: &str, path: &PathBuf) {
+            // slow enough for jobs on other workers to overtake if they could
+            thread::sleep(Duration::from_millis(1));
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", event_name, path.to_str().unwrap()));
+        }
+        fn call_rename(&self, event_name: &str, from: &PathBuf, to: &PathBuf) {
+            self.calls.lock().unwrap().push(format!(
+                "{} {} {}",
+                event_name,
+                from.to_str().unwrap(),
+                to.to_str().unwrap()
+            ));
+        }
+    }
+
+    fn recording_pool(size: usize, calls: &Arc<Mutex<Vec<String>>>) -> WorkerPool {
+        let calls = calls.clone();
+        WorkerPool::new(
+            size,
+            |event_name, _| event_name == "remove",
+            move |jobs| {
+                let evts = RecordingDispatcher {
+                    calls: calls.clone(),
+                };
+                run_jobs(jobs, &evts);
+            },
+        )
+    }
+
+    #[test]
+    fn test_events_for_a_path_keep_their_order() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let pool = recording_pool(4, &calls);
+
+        for i in 0..20 {
+            pool.call("create", &PathBuf::from(format!("{}.txt", i % 5)));
+            pool.call("update", &PathBuf::from(format!("{}.txt", i % 5)));
+        }
+        pool.wait_idle();
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 40);
+        for i in 0..5 {
+            let for_path: Vec<&String> = calls
+                .iter()
+                .filter(|c| c.ends_with(&format!(" {}.txt", i)))
+                .collect();
+            for pair in for_path.chunks(2) {
+                assert!(pair[0].starts_with("create"));
+                assert!(pair[1].starts_with("update"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_exclusive_event_runs_after_everything_before_it() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let pool = recording_pool(4, &calls);
+
+        for i in 0..10 {
+            pool.call("create", &PathBuf::from(format!("folder/{}.txt", i)));
+        }
+        pool.call("remove", &PathBuf::from("folder"));
+        pool.call("create", &PathBuf::from("folder/new.txt"));
+        pool.wait_idle();
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls[10], "remove folder");
+        assert_eq!(calls[11], "create folder/new.txt");
+    }
+
+    #[test]
+    fn test_rename_runs_after_everything_before_it() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let pool = recording_pool(4, &calls);
+
+        for i in 0..10 {
+            pool.call("create", &PathBuf::from(format!("{}.txt", i)));
+        }
+        pool.call_rename("rename", &PathBuf::from("a"), &PathBuf::from("b"));
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 11);
+        assert_eq!(calls[10], "rename a b");
+    }
+
+    #[test]
+    fn test_events_wait_for_exclusive_work_to_finish() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let pool = recording_pool(4, &calls);
+        let mut sender = None;
+
+        pool.call("create", &PathBuf::from("a.txt"));
+        pool.run_exclusive(|| {
+            let other = pool.clone();
+            sender = Some(thread::spawn(move || {
+                other.call("create", &PathBuf::from("b.txt"))
+            }));
+            thread::sleep(Duration::from_millis(20));
+            calls.lock().unwrap().push(String::from("replay"));
+        });
+        sender.unwrap().join().unwrap();
+        pool.wait_idle();
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(*calls, vec!["create a.txt", "replay", "create b.txt"]);
+    }
+
+    #[test]
+    fn test_wait_idle_returns_when_workers_have_stopped() {
+        let pool = WorkerPool::new(4, |_, _| false, |jobs| drop(jobs));
+
+        pool.call("create", &PathBuf::from("a.txt"));
+        pool.wait_idle();
+    }
+}
@@ -0,0 +1,297 @@
+// These run against the Blob service at STORAGE_ENDPOINT, such as Azurite's
+// at http://127.0.0.1:10000/devstoreaccount1, and pass without doing anything
+// when it isn't set. The container named by STORAGE_CONTAINER must already
+// exist.
+extern crate bucket;
+extern crate futures;
+extern crate url;
+
+use bucket::azure_client::AzureAuth;
+use bucket::bucket::{Config, StorageBackend};
+use bucket::reconcile::ReconcilePolicy;
+use bucket::retry::RetryPolicy;
+use bucket::storage::{AsyncStorage, AzureStorage, Precondition, Storage, StorageError};
+use bucket::sync_state;
+use futures::future;
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET};
+
+// Azurite's well known account and key
+const EMULATOR_ACCOUNT: &str = "devstoreaccount1";
+const EMULATOR_KEY: &str =
+    "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+
+fn test_setting(name: &str, default: &str) -> String {
+    env::var(name).unwrap_or_else(|_| String::from(default))
+}
+
+// a SAS token for the container is tested in place of the key when given
+fn test_auth() -> AzureAuth {
+    match env::var("STORAGE_SAS_TOKEN") {
+        Ok(token) => AzureAuth::SasToken(token),
+        Err(_) => AzureAuth::SharedKey(test_setting("STORAGE_MASTER_KEY", EMULATOR_KEY)),
+    }
+}
+
+fn test_config(name: &str, auth: AzureAuth, block_size: usize) -> Option<Config> {
+    let endpoint = env::var("STORAGE_ENDPOINT").ok()?;
+    let root_folder = env::temp_dir().join(format!("bucket-azure-test-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&root_folder);
+    Some(Config {
+        root_folder: root_folder.to_string_lossy().into_owned(),
+        storage_backend: StorageBackend::Azure,
+        storage_account: test_setting("STORAGE_ACCOUNT", EMULATOR_ACCOUNT),
+        azure_auth: auth,
+        root_container_name: test_setting("STORAGE_CONTAINER", "bucket-test"),
+        blob_endpoint: Some(endpoint),
+        poll_interval: Duration::from_secs(60),
+        reconcile_policy: ReconcilePolicy::Download,
+        block_size,
+        retry_policy: RetryPolicy::default(),
+        workers: 1,
+    })
+}
+
+// blobs are created under a prefix of their own, so tests can run together
+fn test_storage(name: &str, block_size: usize) -> Option<(AzureStorage, String)> {
+    let config = test_config(name, test_auth(), block_size)?;
+    let storage = AzureStorage::new(&config).unwrap();
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let prefix = format!(
+        "bucket-test-{}-{}-{}",
+        name,
+        process::id(),
+        started.as_secs()
+    );
+    Some((storage, prefix))
+}
+
+fn upload(storage: &AzureStorage, blob_name: &str, data: &[u8]) -> String {
+    storage
+        .upload(
+            blob_name,
+            &mut io::Cursor::new(data.to_vec()),
+            data.len() as u64,
+        )
+        .unwrap()
+}
+
+fn download(storage: &AzureStorage, blob_name: &str) -> (Vec<u8>, String) {
+    let mut blob = storage.download(blob_name).unwrap();
+    let mut data = Vec::new();
+    blob.content.read_to_end(&mut data).unwrap();
+    (data, blob.etag)
+}
+
+fn clean_up(storage: &AzureStorage, prefix: &str) {
+    // listed names are decoded, but are sent encoded
+    for blob_name in storage.list_folder_blobs(prefix).unwrap() {
+        let blob_name: String = utf8_percent_encode(&blob_name, DEFAULT_ENCODE_SET).collect();
+        let _ = storage.delete(&blob_name);
+    }
+}
+
+#[test]
+fn test_uploaded_blob_can_be_downloaded() {
+    let (storage, prefix) = match test_storage("round-trip", 4 * 1024 * 1024) {
+        Some(test_storage) => test_storage,
+        None => return,
+    };
+    let blob_name = format!("{}/file.txt", prefix);
+
+    let etag = upload(&storage, &blob_name, b"hello");
+
+    assert_eq!(download(&storage, &blob_name), (b"hello".to_vec(), etag));
+    clean_up(&storage, &prefix);
+}
+
+#[test]
+fn test_large_blob_is_uploaded_and_downloaded_in_blocks() {
+    let (storage, prefix) = match test_storage("blocks", 1024) {
+        Some(test_storage) => test_storage,
+        None => return,
+    };
+    let blob_name = format!("{}/large.bin", prefix);
+    let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+
+    let etag = upload(&storage, &blob_name, &data);
+
+    assert_eq!(download(&storage, &blob_name), (data, etag));
+    let blobs = storage.list_blobs_page(&prefix, None).unwrap().blobs;
+    assert_eq!(blobs[0].content_length, 3000);
+    clean_up(&storage, &prefix);
+}
+
+#[test]
+fn test_missing_blob_is_not_found() {
+    let (storage, prefix) = match test_storage("missing", 4 * 1024 * 1024) {
+        Some(test_storage) => test_storage,
+        None => return,
+    };
+    let blob_name = format!("{}/missing.txt", prefix);
+
+    match storage.delete(&blob_name) {
+        Err(StorageError::PathNotFound) => (),
+        r => panic!("expected PathNotFound from delete, got {:?}", r.err()),
+    }
+    match storage.download(&blob_name) {
+        Err(StorageError::PathNotFound) => (),
+        r => panic!("expected PathNotFound from download, got {:?}", r.err()),
+    }
+    match storage.copy(&blob_name, &format!("{}/copy.txt", prefix)) {
+        Err(StorageError::PathNotFound) => (),
+        r => panic!("expected PathNotFound from copy, got {:?}", r.err()),
+    }
+}
+
+#[test]
+fn test_deleted_blob_is_gone() {
+    let (storage, prefix) = match test_storage("delete", 4 * 1024 * 1024) {
+        Some(test_storage) => test_storage,
+        None => return,
+    };
+    let blob_name = format!("{}/file.txt", prefix);
+    upload(&storage, &blob_name, b"hello");
+
+    storage.delete(&blob_name).unwrap();
+
+    assert!(storage.list_folder_blobs(&prefix).unwrap().is_empty());
+}
+
+#[test]
+fn test_upload_with_stale_etag_is_rejected() {
+    let (storage, prefix) = match test_storage("condition", 4 * 1024 * 1024) {
+        Some(test_storage) => test_storage,
+        None => return,
+    };
+    let blob_name = format!("{}/file.txt", prefix);
+    let stale_etag = upload(&storage, &blob_name, b"one");
+    upload(&storage, &blob_name, b"two");
+
+    let result = storage.upload_if_match(
+        &blob_name,
+        &mut io::Cursor::new(b"three".to_vec()),
+        5,
+        &stale_etag,
+    );
+
+    match result {
+        Err(StorageError::ConditionNotMet) => (),
+        r => panic!("expected ConditionNotMet, got {:?}", r),
+    }
+    assert_eq!(download(&storage, &blob_name).0, b"two".to_vec());
+    clean_up(&storage, &prefix);
+}
+
+#[test]
+fn test_upload_if_absent_does_not_replace_blob() {
+    let (storage, prefix) = match test_storage("absent", 4 * 1024 * 1024) {
+        Some(test_storage) => test_storage,
+        None => return,
+    };
+    let blob_name = format!("{}/file.txt", prefix);
+    upload(&storage, &blob_name, b"one");
+
+    let result = storage.upload_if_absent(&blob_name, &mut io::Cursor::new(b"two".to_vec()), 3);
+
+    match result {
+        Err(StorageError::ConditionNotMet) => (),
+        r => panic!("expected ConditionNotMet, got {:?}", r),
+    }
+    assert_eq!(download(&storage, &blob_name).0, b"one".to_vec());
+    clean_up(&storage, &prefix);
+}
+
+#[test]
+fn test_copy_keeps_source() {
+    let (storage, prefix) = match test_storage("copy", 4 * 1024 * 1024) {
+        Some(test_storage) => test_storage,
+        None => return,
+    };
+    let from = format!("{}/from.txt", prefix);
+    let to = format!("{}/to.txt", prefix);
+    upload(&storage, &from, b"hello");
+
+    let etag = storage.copy(&from, &to).unwrap();
+
+    assert_eq!(download(&storage, &from).0, b"hello".to_vec());
+    assert_eq!(download(&storage, &to), (b"hello".to_vec(), etag));
+    clean_up(&storage, &prefix);
+}
+
+#[test]
+fn test_listing_follows_continuation_markers() {
+    let (storage, prefix) = match test_storage("listing", 4 * 1024 * 1024) {
+        Some((storage, prefix)) => (storage.with_list_page_size(2), prefix),
+        None => return,
+    };
+    for i in 0..5 {
+        upload(&storage, &format!("{}/{}.txt", prefix, i), b"hello");
+    }
+    // blob names, and so prefixes, arrive percent encoded
+    let spaced = format!("{}/spaced%20folder", prefix);
+    upload(&storage, &format!("{}/a.txt", spaced), b"hello");
+
+    let first_page = storage.list_blobs_page(&prefix, None).unwrap();
+    let blobs = storage.list_folder_blobs(&prefix).unwrap();
+    let spaced_blobs = storage.list_folder_blobs(&spaced).unwrap();
+
+    assert_eq!(first_page.blobs.len(), 2);
+    assert!(first_page.next_marker.is_some());
+    assert_eq!(blobs.len(), 6);
+    assert_eq!(blobs[0], format!("{}/0.txt", prefix));
+    assert_eq!(
+        spaced_blobs,
+        vec![format!("{}/spaced folder/a.txt", prefix)]
+    );
+    assert_eq!(
+        first_page.blobs[0].content_md5,
+        Some(sync_state::hash(b"hello"))
+    );
+    clean_up(&storage, &prefix);
+}
+
+#[test]
+fn test_async_requests_run_together() {
+    let (storage, prefix) = match test_storage("async", 4 * 1024 * 1024) {
+        Some(test_storage) => test_storage,
+        None => return,
+    };
+    let blob_names: Vec<String> = (0..5).map(|i| format!("{}/{}.txt", prefix, i)).collect();
+
+    let uploads: Vec<_> = blob_names
+        .iter()
+        .map(|blob_name| storage.upload_async(blob_name, b"hello".to_vec(), Precondition::IfAbsent))
+        .collect();
+    let etags = storage.run(Box::new(future::join_all(uploads))).unwrap();
+    let deletes: Vec<_> = blob_names
+        .iter()
+        .map(|blob_name| storage.delete_async(blob_name))
+        .collect();
+    storage.run(Box::new(future::join_all(deletes))).unwrap();
+
+    assert_eq!(etags.len(), 5);
+    assert!(storage.list_folder_blobs(&prefix).unwrap().is_empty());
+}
+
+#[test]
+fn test_wrong_key_is_not_retried() {
+    // "not the key", base64 encoded
+    let auth = AzureAuth::SharedKey(String::from("bm90IHRoZSBrZXk="));
+    let config = match test_config("wrong-key", auth, 4 * 1024 * 1024) {
+        Some(config) => config,
+        None => return,
+    };
+    let storage = AzureStorage::new(&config).unwrap();
+
+    let result = storage.list_blobs_page("bucket-test-wrong-key", None);
+
+    match result {
+        Err(ref e) if !e.is_retryable() => (),
+        r => panic!("expected a permanent error, got {:?}", r),
+    }
+}